@@ -0,0 +1,58 @@
+//! Times the lexer on generated sources of growing size.
+//!
+//! Run with `cargo bench --bench lexer`. Each doubling of the input should
+//! roughly double the time taken, so the time per megabyte stays flat.
+
+#![allow(dead_code)]
+
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/lexer.rs"]
+mod lexer;
+#[path = "../src/span.rs"]
+mod span;
+
+use lexer::Lexer;
+use std::path::PathBuf;
+use std::time::Instant;
+
+const MB: usize = 1024 * 1024;
+
+/// Builds a `.car` file of at least `size` bytes.
+fn generate(size: usize) -> String {
+    let mut src = "cinc \"<stdio.h>\"\n\nextern printf i8$* i32\n\n".to_string();
+
+    let mut i = 0;
+    while src.len() < size {
+        src += &format!(
+            "# proc number {i}, with a comment\n\
+             proc step{i} i32 : i32 {{\n    \
+             => x x 3 * 1.5 ' i32 + x 7 % <= if {{ x 1 + ret }}\n    \
+             \"läuft\" x printf disc 0\n\
+             }}\n\n"
+        );
+        i += 1;
+    }
+
+    src
+}
+
+fn main() {
+    println!("{:>8} {:>12} {:>12}", "size", "time", "per MB");
+
+    for mb in [1, 2, 4, 8] {
+        let src = generate(mb * MB);
+
+        let start = Instant::now();
+        let tokens = Lexer::with_source(PathBuf::from("bench.car"), &src).count();
+        let time = start.elapsed();
+
+        println!(
+            "{:>6}MB {:>12.2?} {:>12.2?}   {} tokens",
+            mb,
+            time,
+            time / mb as u32,
+            tokens
+        );
+    }
+}
@@ -0,0 +1,62 @@
+//! Times the parser on generated sources of growing size.
+//!
+//! Run with `cargo bench --bench parser`. Like the lexer, the time per
+//! megabyte should stay flat as the input grows.
+
+#![allow(dead_code)]
+
+#[path = "../src/error.rs"]
+mod error;
+#[path = "../src/lexer.rs"]
+mod lexer;
+#[path = "../src/parser.rs"]
+mod parser;
+#[path = "../src/span.rs"]
+mod span;
+
+use error::*;
+use lexer::*;
+use parser::*;
+use std::path::PathBuf;
+use std::time::Instant;
+
+const MB: usize = 1024 * 1024;
+
+/// Builds a `.car` file of at least `size` bytes.
+fn generate(size: usize) -> String {
+    let mut src = "cinc \"<stdio.h>\"\n\nextern printf i8$* i32\n\n".to_string();
+
+    let mut i = 0;
+    while src.len() < size {
+        src += &format!(
+            "proc step{i} i32 : i32 {{\n    \
+             => x x 3 * 1 + x 7 % <= if {{ x 1 + ret }} else {{ x 2 + }}\n    \
+             0 while {{ copy 10 < }} {{ 1 + }} disc\n\
+             }}\n\n"
+        );
+        i += 1;
+    }
+
+    src
+}
+
+fn main() {
+    println!("{:>8} {:>12} {:>12}", "size", "time", "per MB");
+
+    for mb in [1, 2, 4, 8] {
+        let src = generate(mb * MB);
+
+        let start = Instant::now();
+        let lex = Lexer::with_source(PathBuf::from("bench.car"), &src);
+        let file = CarpnFile::parse(&mut Tokens::new(lex).unwrap()).unwrap();
+        let time = start.elapsed();
+
+        println!(
+            "{:>6}MB {:>12.2?} {:>12.2?}   {} procs",
+            mb,
+            time,
+            time / mb as u32,
+            file.procs.len()
+        );
+    }
+}
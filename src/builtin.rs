@@ -0,0 +1,254 @@
+//! The words built into the language.
+//!
+//! Every word here only moves entries around on the symbolic stack, so it
+//! produces no C of its own. Each one declares its stack effect in Forth
+//! notation, and for most words the effect is all there is to them: the
+//! names on the left are popped and the names on the right pushed back in
+//! that order. Adding such a word only takes another row in `BUILTINS`.
+
+use crate::cexpr::CExpr;
+use crate::error::CodegenError;
+use crate::visit::{CodegenResult, StackEntry, VisitableCtx};
+
+enum Kind {
+    /// does exactly what its effect says
+    Shuffle,
+    /// takes a count from an integer literal on top of the stack, as in
+    /// `2 pick`, and gives the order the `count + 1` entries below it are
+    /// pushed back in, deepest first
+    Counted(fn(usize) -> Vec<usize>),
+    /// pushes `Self`, the struct being defined
+    SelfType,
+}
+
+pub struct Builtin {
+    pub name: &'static str,
+    pub effect: &'static str,
+    kind: Kind,
+}
+
+const fn shuffle(name: &'static str, effect: &'static str) -> Builtin {
+    Builtin {
+        name,
+        effect,
+        kind: Kind::Shuffle,
+    }
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    shuffle("disc", "( a -- )"),
+    shuffle("copy", "( a -- a a )"),
+    shuffle("swap", "( a b -- b a )"),
+    shuffle("over", "( a b -- a b a )"),
+    shuffle("rot", "( a b c -- b c a )"),
+    shuffle("-rot", "( a b c -- c a b )"),
+    shuffle("nip", "( a b -- b )"),
+    shuffle("tuck", "( a b -- b a b )"),
+    shuffle("2dup", "( a b -- a b a b )"),
+    shuffle("2drop", "( a b -- )"),
+    shuffle("2swap", "( a b c d -- c d a b )"),
+    Builtin {
+        name: "pick",
+        effect: "( xn ... x0 n -- xn ... x0 xn )",
+        kind: Kind::Counted(|n| (0..=n).chain([0]).collect()),
+    },
+    Builtin {
+        name: "roll",
+        effect: "( xn ... x0 n -- xn-1 ... x0 xn )",
+        kind: Kind::Counted(|n| (1..=n).chain([0]).collect()),
+    },
+    Builtin {
+        name: "Self",
+        effect: "( -- Self )",
+        kind: Kind::SelfType,
+    },
+];
+
+/// The builtin word called `name`, if there is one.
+pub fn find(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|b| b.name == name)
+}
+
+/// Fails when `name`, given to a `what` the user defines, is a builtin
+/// word, which would always be run in its place.
+pub fn reserved(name: &str, what: &str) -> Result<(), CodegenError> {
+    match find(name) {
+        Some(_) => Err(CodegenError::BuiltinName {
+            name: name.to_string(),
+            what: what.to_string(),
+        }),
+        None => Ok(()),
+    }
+}
+
+impl Builtin {
+    /// Runs the word on `stack`, which for a word that takes a count no
+    /// longer holds it. `Self` pushes `self_type`.
+    ///
+    /// Both the checker and code generation run words through here, each on
+    /// its own kind of entry.
+    pub fn run<T: Clone>(
+        &self,
+        stack: &mut Vec<T>,
+        count: usize,
+        self_type: T,
+    ) -> Result<(), CodegenError> {
+        let taken = match self.kind {
+            Kind::Shuffle => self.effect_names().0.len(),
+            Kind::Counted(_) => count + 1,
+            Kind::SelfType => {
+                stack.push(self_type);
+                return Ok(());
+            }
+        };
+
+        // before the order is built, which is as long as the count
+        if stack.len() < taken {
+            return Err(CodegenError::Underflow {
+                word: self.name.to_string(),
+                needed: taken,
+                found: stack.len(),
+            });
+        }
+
+        let order = match self.kind {
+            Kind::Counted(order) => order(count),
+            _ => self.order(),
+        };
+
+        let entries = stack.split_off(stack.len() - taken);
+        stack.extend(order.into_iter().map(|i| entries[i].clone()));
+
+        Ok(())
+    }
+
+    /// The order a word that does what its effect says pushes back the
+    /// entries it takes, each an index into them with the deepest first.
+    fn order(&self) -> Vec<usize> {
+        let (inputs, outputs) = self.effect_names();
+
+        outputs
+            .iter()
+            .map(|o| inputs.iter().position(|i| i == o))
+            .collect::<Option<Vec<usize>>>()
+            .unwrap_or_else(|| unreachable!("the effect of `{}` is not a shuffle", self.name))
+    }
+
+    /// Whether the word takes a count from the top of the stack.
+    pub fn is_counted(&self) -> bool {
+        matches!(self.kind, Kind::Counted(_))
+    }
+
+    /// The names either side of the `--` in the effect.
+    fn effect_names(&self) -> (Vec<&'static str>, Vec<&'static str>) {
+        let inner = self.effect.trim_start_matches('(').trim_end_matches(')');
+        let (inputs, outputs) = inner.split_once("--").unwrap_or((inner, ""));
+
+        (
+            inputs.split_whitespace().collect(),
+            outputs.split_whitespace().collect(),
+        )
+    }
+
+    pub fn source(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
+        let count = if self.is_counted() {
+            self.count(ctx)?
+        } else {
+            0
+        };
+
+        let result = self.run(&mut ctx.stack.borrow_mut(), count, StackEntry::SelfType);
+        result.map_err(|e| ctx.error(e))?;
+
+        Ok("".to_string())
+    }
+
+    /// Pops the count for a word such as `pick`, which must be written as
+    /// an integer literal so the stack can be rearranged at compile time.
+    fn count(&self, ctx: &VisitableCtx) -> CodegenResult<usize> {
+        let (count, _, _) = ctx.pop_value(self.name)?;
+
+        let n = match &count {
+            CExpr::Atom(n) => n.parse().ok(),
+            _ => None,
+        };
+
+        n.ok_or_else(|| {
+            ctx.error(CodegenError::BuiltinCount {
+                word: self.name.to_string(),
+                found: count.to_string(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(name: &str, stack: &[&'static str], count: usize) -> Result<Vec<&'static str>, String> {
+        let mut stack = stack.to_vec();
+
+        find(name)
+            .unwrap()
+            .run(&mut stack, count, "Self")
+            .map_err(|e| e.to_string())?;
+        Ok(stack)
+    }
+
+    #[test]
+    fn shuffles_do_what_their_effect_says() {
+        for word in BUILTINS.iter().filter(|b| matches!(b.kind, Kind::Shuffle)) {
+            let (inputs, outputs) = word.effect_names();
+
+            let mut stack = vec!["below"];
+            stack.extend(&inputs);
+            word.run(&mut stack, 0, "Self").unwrap();
+
+            let mut expected = vec!["below"];
+            expected.extend(&outputs);
+            assert_eq!(stack, expected, "{} {}", word.name, word.effect);
+        }
+    }
+
+    #[test]
+    fn counted_words() {
+        assert_eq!(
+            run("pick", &["x2", "x1", "x0"], 0),
+            Ok(vec!["x2", "x1", "x0", "x0"])
+        );
+        assert_eq!(
+            run("pick", &["x2", "x1", "x0"], 2),
+            Ok(vec!["x2", "x1", "x0", "x2"])
+        );
+        assert_eq!(
+            run("roll", &["x2", "x1", "x0"], 0),
+            Ok(vec!["x2", "x1", "x0"])
+        );
+        assert_eq!(
+            run("roll", &["x2", "x1", "x0"], 2),
+            Ok(vec!["x1", "x0", "x2"])
+        );
+    }
+
+    #[test]
+    fn self_pushes_self() {
+        assert_eq!(run("Self", &["a"], 0), Ok(vec!["a", "Self"]));
+    }
+
+    #[test]
+    fn underflow_is_found_before_the_order_is_built() {
+        assert_eq!(
+            run("swap", &["a"], 0),
+            Err("`swap` needs 2 entries, but the stack holds 1".to_string())
+        );
+        assert!(run("pick", &["a"], usize::MAX - 1).is_err());
+        assert!(run("roll", &["a"], usize::MAX - 1).is_err());
+    }
+
+    #[test]
+    fn builtin_names_are_reserved() {
+        assert!(reserved("tuck", "def").is_err());
+        assert!(reserved("tucked", "def").is_ok());
+    }
+}
@@ -0,0 +1,210 @@
+use std::fmt;
+
+const PRIMARY: u8 = 16;
+const POSTFIX: u8 = 15;
+const UNARY: u8 = 14;
+
+/// A C expression built up on the symbolic stack.
+///
+/// Keeping the tree around instead of a flat string lets the generated C
+/// be parenthesised from operator precedence, so it always evaluates in
+/// the order the RPN source asked for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CExpr {
+    /// literals and names
+    Atom(String),
+    /// a prefix operator such as `-`, `!`, `*` or `&`
+    Unary(&'static str, Box<CExpr>),
+    Binary(&'static str, Box<CExpr>, Box<CExpr>),
+    Cast(String, Box<CExpr>),
+    /// `base.name` or `base->name`
+    Member(Box<CExpr>, &'static str, String),
+}
+
+fn binary_prec(op: &str) -> u8 {
+    match op {
+        "*" | "/" | "%" => 13,
+        "+" | "-" => 12,
+        "<<" | ">>" => 11,
+        "<" | "<=" | ">" | ">=" => 10,
+        "==" | "!=" => 9,
+        "&" => 8,
+        "^" => 7,
+        "|" => 6,
+        "&&" => 5,
+        "||" => 4,
+        _ => 0,
+    }
+}
+
+impl CExpr {
+    pub fn atom(s: impl Into<String>) -> Self {
+        CExpr::Atom(s.into())
+    }
+
+    pub fn unary(op: &'static str, a: CExpr) -> Self {
+        CExpr::Unary(op, Box::new(a))
+    }
+
+    pub fn binary(op: &'static str, a: CExpr, b: CExpr) -> Self {
+        CExpr::Binary(op, Box::new(a), Box::new(b))
+    }
+
+    pub fn cast(to: impl Into<String>, a: CExpr) -> Self {
+        CExpr::Cast(to.into(), Box::new(a))
+    }
+
+    /// Takes the address of a local, the way named locals live on the stack.
+    pub fn address(name: impl Into<String>) -> Self {
+        CExpr::unary("&", CExpr::atom(name))
+    }
+
+    /// Returns the operand of a `&` expression.
+    pub fn addressed(&self) -> Option<&CExpr> {
+        match self {
+            CExpr::Unary("&", a) => Some(a),
+            _ => None,
+        }
+    }
+
+    /// `*self`, folding away a leading `&`.
+    pub fn deref(self) -> Self {
+        match self {
+            CExpr::Unary("&", a) => *a,
+            a => CExpr::unary("*", a),
+        }
+    }
+
+    /// Whether the expression is made of literals only, so it means the
+    /// same wherever it is used.
+    pub fn is_constant(&self) -> bool {
+        match self {
+            CExpr::Atom(s) => s.starts_with(|c: char| c.is_ascii_digit() || c == '"' || c == '\''),
+            CExpr::Unary(op, a) => !matches!(*op, "&" | "*") && a.is_constant(),
+            CExpr::Binary(_, a, b) => a.is_constant() && b.is_constant(),
+            CExpr::Cast(_, a) => a.is_constant(),
+            CExpr::Member(..) => false,
+        }
+    }
+
+    fn prec(&self) -> u8 {
+        match self {
+            CExpr::Atom(_) => PRIMARY,
+            CExpr::Member(..) => POSTFIX,
+            CExpr::Unary(..) | CExpr::Cast(..) => UNARY,
+            CExpr::Binary(op, _, _) => binary_prec(op),
+        }
+    }
+
+    /// Renders the expression as an operand that needs at least `min`
+    /// precedence, adding parentheses when it binds more loosely.
+    fn operand(&self, min: u8) -> String {
+        if self.prec() < min {
+            format!("({})", self)
+        } else {
+            self.to_string()
+        }
+    }
+}
+
+impl fmt::Display for CExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CExpr::Atom(s) => write!(f, "{}", s),
+            CExpr::Unary(op, a) => {
+                let a = a.operand(UNARY);
+
+                // keep `- -x` from turning into `--x`
+                if a.starts_with(op.chars().next().unwrap()) {
+                    write!(f, "{}({})", op, a)
+                } else {
+                    write!(f, "{}{}", op, a)
+                }
+            }
+            CExpr::Binary(op, a, b) => {
+                let prec = binary_prec(op);
+
+                // C operators are left associative, so an equal precedence
+                // right operand still needs parentheses
+                write!(f, "{} {} {}", a.operand(prec), op, b.operand(prec + 1))
+            }
+            CExpr::Cast(to, a) => write!(f, "({}){}", to, a.operand(UNARY)),
+            CExpr::Member(a, sep, name) => write!(f, "{}{}{}", a.operand(POSTFIX), sep, name),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn a(s: &str) -> CExpr {
+        CExpr::atom(s)
+    }
+
+    #[test]
+    fn tighter_operands_stay_bare() {
+        let e = CExpr::binary("+", CExpr::binary("*", a("a"), a("b")), a("c"));
+        assert_eq!(e.to_string(), "a * b + c");
+    }
+
+    #[test]
+    fn looser_operands_are_parenthesised() {
+        let e = CExpr::binary("*", CExpr::binary("+", a("a"), a("b")), a("c"));
+        assert_eq!(e.to_string(), "(a + b) * c");
+    }
+
+    #[test]
+    fn equal_precedence_keeps_left_associativity() {
+        let left = CExpr::binary("-", CExpr::binary("-", a("a"), a("b")), a("c"));
+        assert_eq!(left.to_string(), "a - b - c");
+
+        let right = CExpr::binary("-", a("a"), CExpr::binary("-", a("b"), a("c")));
+        assert_eq!(right.to_string(), "a - (b - c)");
+    }
+
+    #[test]
+    fn unary_and_cast_operands() {
+        let neg = CExpr::unary("-", CExpr::binary("+", a("a"), a("b")));
+        assert_eq!(neg.to_string(), "-(a + b)");
+
+        let cast = CExpr::cast("float", CExpr::binary("/", a("a"), a("b")));
+        assert_eq!(cast.to_string(), "(float)(a / b)");
+
+        let not = CExpr::unary("!", CExpr::cast("int", a("x")));
+        assert_eq!(not.to_string(), "!(int)x");
+    }
+
+    #[test]
+    fn repeated_prefix_operators_do_not_merge() {
+        let e = CExpr::unary("-", CExpr::unary("-", a("x")));
+        assert_eq!(e.to_string(), "-(-x)");
+    }
+
+    #[test]
+    fn member_access_binds_tightest() {
+        let e = CExpr::Member(Box::new(CExpr::address("p")), ".", "x".to_string());
+        assert_eq!(e.to_string(), "(&p).x");
+
+        let e = CExpr::Member(Box::new(a("p")), "->", "x".to_string());
+        assert_eq!(CExpr::unary("*", e).to_string(), "*p->x");
+    }
+
+    #[test]
+    fn deref_folds_address() {
+        assert_eq!(CExpr::address("x").deref(), a("x"));
+        assert_eq!(a("p").deref().to_string(), "*p");
+    }
+
+    #[test]
+    fn constants_are_made_of_literals() {
+        assert!(CExpr::unary("-", a("5")).is_constant());
+        assert!(CExpr::cast("void*", a("0")).is_constant());
+        assert!(CExpr::binary("+", a("'a'"), a("1.5f")).is_constant());
+        assert!(a("\"hi\"").is_constant());
+
+        assert!(!a("anon_0").is_constant());
+        assert!(!CExpr::binary("+", a("x"), a("1")).is_constant());
+        assert!(!CExpr::unary("*", a("0")).is_constant());
+    }
+}
@@ -0,0 +1,693 @@
+//! Checks the stack effect of every proc before any C is generated.
+//!
+//! Each proc body is run over a stack that only tracks what kind of entry
+//! is where, starting from the arguments its prototype declares. Every
+//! branch and call is followed, and the effect the body turns out to have,
+//! `( in -- out )`, must match the prototype. A failure is reported along
+//! with the stack as it was at that point.
+//!
+//! The depth of the stack is only checked here: once every proc passes,
+//! code generation relies on both arms of an `if` and every pass through a
+//! loop leaving the stack as deep as each other. So anything that stops the
+//! check of a proc early, such as an unknown name, is reported too.
+
+use crate::builtin::{self, Builtin};
+use crate::error::{CarpnError, CodegenError, StackReport};
+use crate::parser::*;
+use crate::span::{Span, Spanned};
+use crate::visit::{ScopeKind, StackEntry, VisitableCtx};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Clone)]
+enum Entry<'a> {
+    /// a value, with the source that pushed it
+    Value(String),
+    /// an integer literal, which words such as `pick` take as a count
+    Literal(usize),
+    Type(String),
+    Struct(String, &'a VisitableCtx),
+    SelfType,
+}
+
+impl fmt::Display for Entry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Entry::Value(v) => write!(f, "{}", v),
+            Entry::Literal(n) => write!(f, "{}", n),
+            Entry::Type(t) => write!(f, "type {}", t),
+            Entry::Struct(name, _) => write!(f, "struct {}", name),
+            Entry::SelfType => write!(f, "Self"),
+        }
+    }
+}
+
+impl Entry<'_> {
+    /// How the entry reads in an error message.
+    fn describe(&self) -> String {
+        match self {
+            Entry::Value(v) => format!("value `{}`", v),
+            Entry::Literal(n) => format!("value `{}`", n),
+            Entry::Type(t) => format!("type `{}`", t),
+            Entry::Struct(name, _) => format!("struct `{}`", name),
+            Entry::SelfType => "`Self`".to_string(),
+        }
+    }
+}
+
+type Check<T> = Result<T, Box<Spanned<StackReport>>>;
+
+/// Checks every proc in `file`, using the names `ctx` was given while
+/// generating its header.
+pub fn check(file: &CarpnFile, ctx: &VisitableCtx) -> Result<(), CarpnError> {
+    let mut reports = Vec::new();
+
+    check_in(&mut reports, ctx, &file.structs, &file.procs);
+
+    if !reports.is_empty() {
+        return Err(CarpnError::Check(reports));
+    }
+
+    Ok(())
+}
+
+fn check_in(
+    reports: &mut Vec<Spanned<StackReport>>,
+    ctx: &VisitableCtx,
+    structs: &[Struct],
+    procs: &[Proc],
+) {
+    for s in structs {
+        if let Some(StackEntry::Struct(_, inner)) = ctx.lookup(&s.name) {
+            check_in(reports, inner, &s.structs, &s.procs);
+        }
+    }
+
+    for p in procs {
+        if let Err(report) = Checker::proc(ctx, p) {
+            reports.push(*report);
+        }
+    }
+}
+
+struct Checker<'a> {
+    /// the file or struct the proc is in, where names are looked up
+    ctx: &'a VisitableCtx,
+    /// the names bound in the proc, innermost block last, each with where
+    /// it was bound
+    locals: Vec<HashMap<String, (Entry<'a>, Span)>>,
+    /// names whose block has ended, for errors
+    ended: HashMap<String, Span>,
+    stack: Vec<Entry<'a>>,
+    /// how many values `ret` takes
+    results: usize,
+    expanding: Vec<String>,
+    span: Span,
+}
+
+impl<'a> Checker<'a> {
+    fn proc(ctx: &'a VisitableCtx, p: &Proc) -> Check<()> {
+        let ProcBody::Impl(body) = &p.body else {
+            return Ok(());
+        };
+        let mut checker = Checker {
+            ctx,
+            locals: vec![HashMap::new()],
+            ended: HashMap::new(),
+            stack: Vec::new(),
+            results: 0,
+            expanding: Vec::new(),
+            span: p.span.clone(),
+        };
+
+        let Some(data) = ctx.procs.get(&p.def.name) else {
+            return Err(checker.unknown(&p.def.name));
+        };
+        checker.results = data.results.len();
+
+        // named parameters are locals, the rest start on the stack
+        for (i, name) in data.names.iter().enumerate() {
+            match name {
+                Some(name) => checker.bind(name, Entry::Value(name.clone())),
+                None => checker.stack.push(Entry::Value(format!("arg_{}", i))),
+            }
+        }
+
+        let inputs = checker.stack.len();
+        let diverges = checker.body(body)?;
+
+        checker.span = p.span.clone();
+        if !diverges && checker.stack.len() != data.results.len() {
+            return Err(checker.fail(
+                CodegenError::EffectMismatch {
+                    name: p.def.name.clone(),
+                    found: effect(inputs, checker.stack.len()),
+                    declared: effect(inputs, data.results.len()),
+                },
+                vec![checker.describe("stack")],
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn fail(&self, error: CodegenError, notes: Vec<String>) -> Box<Spanned<StackReport>> {
+        Box::new(Spanned {
+            node: StackReport { error, notes },
+            span: self.span.clone(),
+        })
+    }
+
+    /// The failure for a use of `name` that found nothing, which says so
+    /// when `name` was bound in a block that has since ended.
+    fn unknown(&self, name: &str) -> Box<Spanned<StackReport>> {
+        let error = match self.ended.get(name) {
+            Some(span) => CodegenError::OutOfScope {
+                name: name.to_string(),
+                kind: ScopeKind::Block.to_string(),
+                declared: span.to_string(),
+            },
+            None => CodegenError::UnknownIdentifier(name.to_string()),
+        };
+
+        self.fail(error, vec![self.describe("stack")])
+    }
+
+    /// The stack as a note, bottom first.
+    fn describe(&self, label: &str) -> String {
+        show_stack(label, &self.stack)
+    }
+
+    fn bind(&mut self, name: &str, entry: Entry<'a>) {
+        let span = self.span.clone();
+
+        if let Some(scope) = self.locals.last_mut() {
+            scope.insert(name.to_string(), (entry, span));
+        }
+    }
+
+    fn local(&self, name: &str) -> Option<Entry<'a>> {
+        self.locals
+            .iter()
+            .rev()
+            .find_map(|s| s.get(name))
+            .map(|(entry, _)| entry.clone())
+    }
+
+    /// Ends the innermost block, forgetting the names bound in it.
+    fn end_block(&mut self) {
+        for (name, (_, span)) in self.locals.pop().into_iter().flatten() {
+            self.ended.insert(name, span);
+        }
+    }
+
+    /// Makes sure `word` has `needed` entries to take.
+    fn need(&self, word: &str, needed: usize) -> Check<()> {
+        if self.stack.len() < needed {
+            return Err(self.fail(
+                CodegenError::Underflow {
+                    word: word.to_string(),
+                    needed,
+                    found: self.stack.len(),
+                },
+                vec![self.describe("stack")],
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn pop(&mut self, word: &str) -> Check<Entry<'a>> {
+        self.need(word, 1)?;
+
+        let Some(top) = self.stack.pop() else {
+            unreachable!("`need` made sure the stack holds an entry");
+        };
+        Ok(top)
+    }
+
+    /// Pops `count` entries for `word` and pushes the value it leaves, in
+    /// the postfix form it was written in.
+    fn apply(&mut self, word: &str, count: usize) -> Check<()> {
+        self.need(word, count)?;
+
+        let args = self.stack.split_off(self.stack.len() - count);
+        let mut text: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        text.push(word.to_string());
+
+        self.stack.push(Entry::Value(text.join(" ")));
+        Ok(())
+    }
+
+    /// Runs `body`, giving whether control never gets past its end.
+    fn body(&mut self, body: &[Spanned<Expression>]) -> Check<bool> {
+        for e in body {
+            self.span = e.span.clone();
+            self.expr(&e.node)?;
+        }
+
+        Ok(diverges(body))
+    }
+
+    /// Runs `body` on its own copy of `entry`, giving the stack it leaves
+    /// and whether control never gets past its end.
+    fn run(
+        &mut self,
+        body: &[Spanned<Expression>],
+        entry: &[Entry<'a>],
+    ) -> Check<(Vec<Entry<'a>>, bool)> {
+        let span = self.span.clone();
+        self.stack = entry.to_vec();
+        let diverges = self.body(body)?;
+        self.span = span;
+
+        Ok((std::mem::take(&mut self.stack), diverges))
+    }
+
+    /// Runs `body` like `run`, in a block of its own.
+    fn block(
+        &mut self,
+        body: &[Spanned<Expression>],
+        entry: &[Entry<'a>],
+    ) -> Check<(Vec<Entry<'a>>, bool)> {
+        self.locals.push(HashMap::new());
+        let result = self.run(body, entry)?;
+        self.end_block();
+
+        Ok(result)
+    }
+
+    fn expr(&mut self, expr: &Expression) -> Check<()> {
+        match expr {
+            Expression::Int(i, suffix) => {
+                let literal = usize::try_from(*i).ok().filter(|_| suffix.is_none());
+                self.stack.push(match literal {
+                    Some(n) => Entry::Literal(n),
+                    None => Entry::Value(expr.to_string()),
+                });
+            }
+            Expression::Float(..) | Expression::String(_) | Expression::Char(_) => {
+                self.stack.push(Entry::Value(expr.to_string()));
+            }
+            Expression::Ident(name) => self.word(name, self.ctx, true)?,
+            Expression::Prop(p) => match self.pop(&expr.to_string())? {
+                top @ (Entry::Value(_) | Entry::Literal(_)) => {
+                    self.stack.push(Entry::Value(format!("{}.{}", top, p)))
+                }
+                Entry::Type(t) => self.stack.push(Entry::Type(format!("{}.{}", t, p))),
+                Entry::Struct(_, inner) => self.word(p, inner, false)?,
+                Entry::SelfType => self.word(p, self.ctx, true)?,
+            },
+            Expression::Op(op) => self.op(op)?,
+            Expression::Return => {
+                self.need("ret", self.results.max(1))?;
+                let at = self.stack.len() - self.results.max(1);
+                self.stack.truncate(at);
+            }
+            Expression::If(i) => {
+                self.pop("if")?;
+                let entry = self.stack.clone();
+
+                let then = self.block(&i.body, &entry)?;
+                let other = match &i.else_body {
+                    Some(body) => self.block(body, &entry)?,
+                    None => (entry, false),
+                };
+
+                self.stack = match (then, other) {
+                    ((_, true), (stack, _)) | ((stack, false), (_, true)) => stack,
+                    ((then, false), (other, false)) if then.len() == other.len() => then,
+                    ((then, false), (other, false)) => {
+                        return Err(self.fail(
+                            CodegenError::BranchDepth(then.len(), other.len()),
+                            vec![show_stack("then", &then), show_stack("else", &other)],
+                        ));
+                    }
+                };
+            }
+            Expression::While(w) => {
+                let entry = self.stack.clone();
+
+                // names bound in the condition last until the end of the body
+                self.locals.push(HashMap::new());
+                let (mut cond, _) = self.run(&w.cond, &entry)?;
+                if cond.len() != entry.len() + 1 {
+                    self.stack = cond;
+                    return Err(self.fail(
+                        CodegenError::ConditionDepth {
+                            expected: entry.len() + 1,
+                            found: self.stack.len(),
+                        },
+                        vec![show_stack("entry", &entry), self.describe("stack")],
+                    ));
+                }
+                cond.pop();
+
+                self.loop_body(&w.body, cond, "while body")?;
+                self.end_block();
+                self.stack = entry;
+            }
+            Expression::Loop(l) => {
+                let entry = self.stack.clone();
+                self.loop_body(&l.body, entry, "loop body")?;
+            }
+            Expression::Param(_) => unreachable!("parameters only appear in prototypes"),
+        }
+
+        Ok(())
+    }
+
+    /// Runs the body of a loop, which must leave the stack as deep as it
+    /// found it.
+    fn loop_body(
+        &mut self,
+        body: &[Spanned<Expression>],
+        entry: Vec<Entry<'a>>,
+        what: &str,
+    ) -> Check<()> {
+        let (after, diverges) = self.block(body, &entry)?;
+
+        if !diverges && after.len() != entry.len() {
+            self.stack = after;
+            return Err(self.fail(
+                CodegenError::LoopDepth {
+                    what: what.to_string(),
+                    expected: entry.len(),
+                    found: self.stack.len(),
+                },
+                vec![show_stack("entry", &entry), self.describe("stack")],
+            ));
+        }
+
+        self.stack = entry;
+        Ok(())
+    }
+
+    fn op(&mut self, op: &ExprOp) -> Check<()> {
+        let word = op.to_string();
+
+        match op {
+            ExprOp::Name(n) => {
+                builtin::reserved(n, "binding").map_err(|e| self.fail(e, Vec::new()))?;
+                let top = self.pop(&word)?;
+                self.bind(n, top);
+            }
+            ExprOp::Star => match self.stack.last() {
+                Some(Entry::Type(t)) => {
+                    let t = format!("{}*", t);
+                    self.stack.pop();
+                    self.stack.push(Entry::Type(t));
+                }
+                _ => self.apply(&word, 2)?,
+            },
+            ExprOp::Dollar => match self.pop(&word)? {
+                Entry::Type(t) => self.stack.push(Entry::Type(format!("{}$", t))),
+                top => {
+                    return Err(self.fail(
+                        CodegenError::ExpectedTypeFoundValue {
+                            word,
+                            found: top.describe(),
+                        },
+                        vec![self.describe("stack")],
+                    ))
+                }
+            },
+            ExprOp::Assign => {
+                self.need(&word, 2)?;
+                self.stack.truncate(self.stack.len() - 2);
+            }
+            ExprOp::Deref | ExprOp::Not | ExprOp::Negate => self.apply(&word, 1)?,
+            ExprOp::Tick
+            | ExprOp::Equal
+            | ExprOp::Minus
+            | ExprOp::Plus
+            | ExprOp::LessThan
+            | ExprOp::GreaterThan
+            | ExprOp::Divide
+            | ExprOp::Modulo
+            | ExprOp::BitAnd
+            | ExprOp::BitOr
+            | ExprOp::BitXor
+            | ExprOp::ShiftLeft
+            | ExprOp::ShiftRight
+            | ExprOp::LessEqual
+            | ExprOp::GreaterEqual
+            | ExprOp::NotEqual
+            | ExprOp::And
+            | ExprOp::Or => self.apply(&word, 2)?,
+        }
+
+        Ok(())
+    }
+
+    /// Runs the word `name`, looked up in `scope` and, when `locals` is set,
+    /// the names bound in the proc.
+    fn word(&mut self, name: &str, scope: &'a VisitableCtx, locals: bool) -> Check<()> {
+        if let Some(word) = builtin::find(name) {
+            // a type on top would make this a declaration, as in `i32 tuck`
+            if locals && matches!(self.stack.last(), Some(Entry::Type(_) | Entry::Struct(..))) {
+                builtin::reserved(name, "local").map_err(|e| self.fail(e, Vec::new()))?;
+            }
+            return self.builtin(word);
+        }
+
+        if let Some(def) = scope.defs.get(name) {
+            if self.expanding.iter().any(|d| d == name) {
+                return Err(self.fail(CodegenError::RecursiveDef(name.to_string()), Vec::new()));
+            }
+
+            self.expanding.push(name.to_string());
+            let span = self.span.clone();
+            self.body(&def.body)?;
+            self.span = span;
+            self.expanding.pop();
+
+            return Ok(());
+        }
+
+        if let Some(entry) = self.local(name).filter(|_| locals) {
+            self.stack.push(entry);
+            return Ok(());
+        }
+
+        if let Some(entry) = scope.lookup(name) {
+            self.stack.push(match entry {
+                StackEntry::Type(_) => Entry::Type(name.to_string()),
+                StackEntry::Value(..) | StackEntry::Field(..) => Entry::Value(name.to_string()),
+                StackEntry::Struct(_, inner) => Entry::Struct(name.to_string(), inner),
+                StackEntry::SelfType => Entry::SelfType,
+            });
+            return Ok(());
+        }
+
+        if let Some(data) = scope.procs.get(name) {
+            self.need(name, data.args.len())?;
+            self.stack.truncate(self.stack.len() - data.args.len());
+
+            for _ in &data.results {
+                self.stack.push(Entry::Value(name.to_string()));
+            }
+            return Ok(());
+        }
+
+        // anything else declares a local of the type on top
+        match self.stack.last() {
+            Some(Entry::Type(_) | Entry::Struct(..)) if locals => {
+                self.stack.pop();
+                self.bind(name, Entry::Value(name.to_string()));
+                Ok(())
+            }
+            _ => Err(self.unknown(name)),
+        }
+    }
+
+    fn builtin(&mut self, word: &Builtin) -> Check<()> {
+        let count = if word.is_counted() {
+            match self.pop(word.name)? {
+                Entry::Literal(n) => n,
+                top => {
+                    return Err(self.fail(
+                        CodegenError::BuiltinCount {
+                            word: word.name.to_string(),
+                            found: top.to_string(),
+                        },
+                        vec![self.describe("stack")],
+                    ))
+                }
+            }
+        } else {
+            0
+        };
+
+        let notes = vec![
+            format!("`{}` has the effect {}", word.name, word.effect),
+            self.describe("stack"),
+        ];
+        let result = word.run(&mut self.stack, count, Entry::SelfType);
+        result.map_err(|e| self.fail(e, notes))
+    }
+}
+
+/// A stack effect in Forth notation, by how many entries are on each side.
+fn effect(inputs: usize, outputs: usize) -> String {
+    format!("( {} -- {} )", inputs, outputs)
+}
+
+fn show_stack(label: &str, stack: &[Entry]) -> String {
+    let entries: Vec<String> = stack.iter().map(|e| e.to_string()).collect();
+
+    format!("{}: [{}]", label, entries.join(", "))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use crate::visit::Visitable;
+    use std::path::PathBuf;
+
+    /// The messages of every stack error in `src`, or of the error that
+    /// stopped its header.
+    fn errors(src: &str) -> Vec<String> {
+        let lex = Lexer::with_source(PathBuf::from("test.car"), src);
+        let file = CarpnFile::parse(&mut Tokens::new(lex).unwrap()).unwrap();
+
+        let mut ctx = VisitableCtx::for_test();
+        ctx.declare_prelude();
+        if let Err(e) = file.header(&mut ctx) {
+            return vec![e.to_string()];
+        }
+
+        match check(&file, &ctx) {
+            Err(CarpnError::Check(reports)) => {
+                reports.iter().map(|r| r.node.error.to_string()).collect()
+            }
+            Err(e) => panic!("{}", e),
+            Ok(()) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn branch_depth() {
+        assert!(errors("proc f : i32 { 1 if { 2 } else { 3 } }").is_empty());
+        assert!(errors("proc f : i32 { 1 if { 2 ret } 3 }").is_empty());
+        assert_eq!(
+            errors("proc f : i32 { 1 if { 2 } }"),
+            ["if branches leave different stack depths: 1 entries and 0 entries"]
+        );
+    }
+
+    #[test]
+    fn loop_depth() {
+        assert!(errors("proc f : i32 { 0 while { copy 5 < } { 1 + } }").is_empty());
+        assert_eq!(
+            errors("proc f { loop { 1 } }"),
+            ["loop body must leave the stack as deep as it found it: expected 0 entries, found 1"]
+        );
+        assert_eq!(
+            errors("proc f : i32 { 0 while { copy 5 < } { disc } }"),
+            ["while body must leave the stack as deep as it found it: expected 1 entries, found 0"]
+        );
+        assert_eq!(
+            errors("proc f : i32 { 0 while { copy copy 5 < } { 1 + } }"),
+            ["while condition must leave 2 entries on the stack, found 3"]
+        );
+    }
+
+    #[test]
+    fn result_count() {
+        assert!(errors("proc f i32 : i32 i32 { 1 }").is_empty());
+        assert_eq!(
+            errors("proc f : i32 i32 { 1 }"),
+            ["proc `f` has the stack effect ( 0 -- 1 ), but its prototype declares ( 0 -- 2 )"]
+        );
+        assert_eq!(
+            errors("proc f a:i32 b:i32 : i32 { + }"),
+            ["`+` needs 2 entries, but the stack holds 0"]
+        );
+    }
+
+    #[test]
+    fn void_returns_nothing() {
+        assert!(errors("proc f : void { }").is_empty());
+        assert_eq!(
+            errors("proc f : void { 1 }"),
+            ["proc `f` has the stack effect ( 0 -- 1 ), but its prototype declares ( 0 -- 0 )"]
+        );
+    }
+
+    #[test]
+    fn arms_and_loops_that_never_fall_through() {
+        assert!(errors("proc f x:i32 : i32 { x if { 1 ret } else { 2 ret } }").is_empty());
+        assert!(errors("proc f x:i32 : i32 { loop { x ret } }").is_empty());
+        assert!(errors("proc f x:i32 : i32 { x if { 1 ret } else { loop { 2 ret } } }").is_empty());
+        assert_eq!(
+            errors("proc f x:i32 : i32 { x if { 1 ret } }"),
+            ["proc `f` has the stack effect ( 0 -- 0 ), but its prototype declares ( 0 -- 1 )"]
+        );
+    }
+
+    #[test]
+    fn every_proc_is_reported() {
+        assert_eq!(errors("proc f { 1 } proc g : i32 { } proc h { }").len(), 2);
+    }
+
+    #[test]
+    fn unknown_words_are_reported() {
+        assert_eq!(
+            errors("proc f : i32 { nope }"),
+            ["unknown identifier `nope`"]
+        );
+        assert_eq!(
+            errors("def d { d } proc f { d }"),
+            ["def `d` refers to itself"]
+        );
+        assert_eq!(
+            errors("proc f : i32 { 1 1 1 + pick }"),
+            ["`pick` takes its count from an integer literal, found `1 1 +`"]
+        );
+        assert_eq!(
+            errors("proc f : i32 { 1 if { 2 => x } x }"),
+            ["`x` is out of scope here, it was declared in a block at test.car:1:25"]
+        );
+    }
+
+    #[test]
+    fn names_bound_in_a_condition_last_through_the_body() {
+        assert!(
+            errors("proc f : i32 { 0 while { copy 5 < copy => c } { c disc 1 + } }").is_empty()
+        );
+    }
+
+    #[test]
+    fn builtin_names_are_reserved() {
+        assert_eq!(
+            errors("def tuck { 1 } proc f : i32 { 2 tuck }"),
+            ["`tuck` is a builtin word and can't name a def"]
+        );
+        assert_eq!(
+            errors("proc f : i32 { 7 => rot rot }"),
+            ["`rot` is a builtin word and can't name a binding"]
+        );
+        assert_eq!(
+            errors("proc f copy:i32 : i32 { 1 }"),
+            ["`copy` is a builtin word and can't name a parameter"]
+        );
+        assert_eq!(
+            errors("struct A { proc swap { } }"),
+            ["`swap` is a builtin word and can't name a proc"]
+        );
+        assert_eq!(
+            errors("proc f : i32 { 1 i32 tuck }"),
+            ["`tuck` is a builtin word and can't name a local"]
+        );
+    }
+
+    #[test]
+    fn huge_counts_underflow() {
+        assert_eq!(
+            errors("proc f : i32 { 1 2147483647 pick }"),
+            ["`pick` needs 2147483648 entries, but the stack holds 1"]
+        );
+    }
+}
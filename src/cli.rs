@@ -1,4 +1,4 @@
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 use std::path::PathBuf;
 
 #[derive(Parser, Debug)]
@@ -8,6 +8,51 @@ pub struct CompileArgs {
 
     #[arg(long, default_value = "gcc")]
     pub cc: String,
+
+    /// Print a stage of the pipeline instead of compiling
+    #[arg(long, value_enum)]
+    pub dump: Option<Stage>,
+}
+
+#[derive(Parser, Debug)]
+pub struct ParseArgs {
+    #[arg(required = true)]
+    pub input: Vec<PathBuf>,
+
+    #[arg(long, value_enum, default_value = "text")]
+    pub format: Format,
+}
+
+#[derive(Parser, Debug)]
+pub struct FmtArgs {
+    #[arg(required = true)]
+    pub input: Vec<PathBuf>,
+
+    /// Report files that are not formatted instead of rewriting them
+    #[arg(long)]
+    pub check: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    /// The syntax tree, as `--dump ast` prints it
+    Text,
+    /// A versioned JSON document with the tokens and the syntax tree
+    Json,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq)]
+pub enum Stage {
+    /// The tokens, one per line
+    Tokens,
+    /// The syntax tree
+    Ast,
+    /// The procs, defs and structs and the C names they get
+    Symbols,
+    /// The stack after each word
+    Stack,
+    /// The generated header and source
+    C,
 }
 
 #[derive(Parser, Debug)]
@@ -15,4 +60,8 @@ pub struct CompileArgs {
 pub enum Args {
     #[command(version, about, long_about = "Compiles a program")]
     C(CompileArgs),
+    #[command(about, long_about = "Parses a program and prints its syntax tree")]
+    Parse(ParseArgs),
+    #[command(about, long_about = "Rewrites programs in the canonical layout")]
+    Fmt(FmtArgs),
 }
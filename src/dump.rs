@@ -0,0 +1,212 @@
+use crate::lexer::Lexer;
+use crate::parser::*;
+use crate::span::{Span, Spanned};
+use crate::visit::{ProcData, StackEntry, VisitableCtx};
+use crate::walk::{walk_expr, Visit};
+
+/// Each token on its own line, after where it starts.
+pub fn tokens(lex: Lexer) -> String {
+    let mut result = "".to_string();
+
+    for token in lex.flatten() {
+        result += &format!("{}  {}\n", token.span, token.node);
+    }
+
+    result
+}
+
+/// The syntax tree, one node to a line and indented under its parent.
+pub fn ast(file: &CarpnFile) -> String {
+    let mut out = Tree::default();
+    out.visit_file(file);
+
+    out.text
+}
+
+#[derive(Default)]
+struct Tree {
+    text: String,
+    depth: usize,
+}
+
+impl Tree {
+    fn line(&mut self, label: &str, span: &Span) {
+        self.text += &format!(
+            "{}{} @{}:{}\n",
+            "  ".repeat(self.depth),
+            label,
+            span.line,
+            span.column
+        );
+    }
+
+    fn label(&mut self, label: &str) {
+        self.text += &format!("{}{}\n", "  ".repeat(self.depth), label);
+    }
+
+    fn doc(&mut self, doc: &Option<String>) {
+        if let Some(doc) = doc {
+            self.label(&format!("doc {:?}", doc));
+        }
+    }
+
+    /// Writes `body` one level in, under `label`.
+    fn labelled(&mut self, label: &str, body: &[Spanned<Expression>]) {
+        self.label(label);
+        self.depth += 1;
+        self.visit_body(body);
+        self.depth -= 1;
+    }
+}
+
+impl Visit for Tree {
+    fn visit_include(&mut self, i: &Include) {
+        let kind = if i.is_c { "cinc" } else { "inc" };
+        self.line(&format!("{} {:?}", kind, i.file), &i.span);
+    }
+
+    fn visit_def(&mut self, d: &Def) {
+        self.line(&format!("def {}", d.name), &d.span);
+        self.depth += 1;
+        self.doc(&d.doc);
+        self.labelled("body", &d.body);
+        self.depth -= 1;
+    }
+
+    fn visit_struct(&mut self, s: &Struct) {
+        self.line(&format!("struct {}", s.name), &s.span);
+        self.depth += 1;
+        self.doc(&s.doc);
+        self.labelled("fields", &s.body);
+        for d in &s.defs {
+            self.visit_def(d);
+        }
+        for s in &s.structs {
+            self.visit_struct(s);
+        }
+        for p in &s.procs {
+            self.visit_proc(p);
+        }
+        self.depth -= 1;
+    }
+
+    fn visit_proc(&mut self, p: &Proc) {
+        let kind = match p.body {
+            ProcBody::Extern => "extern",
+            ProcBody::Impl(_) => "proc",
+        };
+
+        self.line(&format!("{} {}", kind, p.def.name), &p.span);
+        self.depth += 1;
+        self.doc(&p.doc);
+        self.labelled("args", &p.def.args);
+        if let Some(result) = &p.def.result {
+            self.labelled("result", result);
+        }
+        if let ProcBody::Impl(body) = &p.body {
+            self.labelled("body", body);
+        }
+        self.depth -= 1;
+    }
+
+    fn visit_expr(&mut self, e: &Spanned<Expression>) {
+        let kind = match &e.node {
+            Expression::Int(..) => "int",
+            Expression::Float(..) => "float",
+            Expression::Ident(_) => "ident",
+            Expression::Op(_) => "op",
+            Expression::String(_) => "string",
+            Expression::Char(_) => "char",
+            Expression::Prop(_) => "prop",
+            Expression::Param(_) => "param",
+            Expression::Return | Expression::If(_) | Expression::While(_) | Expression::Loop(_) => {
+                ""
+            }
+        };
+
+        if kind.is_empty() {
+            self.line(&e.node.to_string(), &e.span);
+        } else {
+            self.line(&format!("{} {}", kind, e.node), &e.span);
+        }
+
+        self.depth += 1;
+        match &e.node {
+            Expression::If(i) => {
+                self.labelled("then", &i.body);
+                if let Some(else_body) = &i.else_body {
+                    self.labelled("else", else_body);
+                }
+            }
+            Expression::While(w) => {
+                self.labelled("cond", &w.cond);
+                self.labelled("body", &w.body);
+            }
+            Expression::Param(p) => self.labelled("type", &p.kind),
+            _ => walk_expr(self, e),
+        }
+        self.depth -= 1;
+    }
+}
+
+/// The procs, defs and structs of `file`, with the C names and types the
+/// code generator gave them.
+pub fn symbols(file: &CarpnFile, ctx: &VisitableCtx) -> String {
+    let mut result = "".to_string();
+
+    symbols_in(&mut result, 0, ctx, &file.defs, &file.structs, &file.procs);
+
+    result
+}
+
+fn symbols_in(
+    result: &mut String,
+    depth: usize,
+    ctx: &VisitableCtx,
+    defs: &[Def],
+    structs: &[Struct],
+    procs: &[Proc],
+) {
+    let ind = "    ".repeat(depth);
+
+    for d in defs {
+        *result += &format!("{}def {}\n", ind, d.name);
+    }
+
+    for s in structs {
+        let Some(StackEntry::Struct(c_name, inner)) = ctx.lookup(&s.name) else {
+            continue;
+        };
+
+        *result += &format!("{}struct {} -> {}\n", ind, s.name, c_name);
+        symbols_in(result, depth + 1, inner, &s.defs, &s.structs, &s.procs);
+    }
+
+    for p in procs {
+        let Some(data) = ctx.procs.get(&p.def.name) else {
+            continue;
+        };
+
+        *result += &format!("{}{}\n", ind, proc_symbol(&p.def.name, data));
+    }
+}
+
+fn proc_symbol(name: &str, data: &ProcData) -> String {
+    let kind = if data.is_extern { "extern" } else { "proc" };
+    let args: Vec<String> = data
+        .args
+        .iter()
+        .zip(&data.names)
+        .map(|(kind, name)| match name {
+            Some(name) => format!("{}: {}", name, kind),
+            None => kind.clone(),
+        })
+        .collect();
+    let mut result = format!("{} {}({})", kind, name, args.join(", "));
+
+    if !data.results.is_empty() {
+        result += &format!(" : {}", data.results.join(" "));
+    }
+
+    result + &format!(" -> {}", data.full_name)
+}
@@ -1,9 +1,81 @@
+use crate::span::{Span, Spanned};
+use serde::Serialize;
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 #[derive(Error, Debug)]
 pub enum CarpnError {
-    #[error("parser error: {0}")]
-    Parse(#[from] ParseError),
+    #[error("found {} invalid tokens", .0.len())]
+    Lex(Vec<Spanned<LexError>>),
+    #[error("found {} syntax errors", .0.len())]
+    Parse(Vec<Spanned<ParseError>>),
+    #[error("found {} stack errors", .0.len())]
+    Check(Vec<Spanned<StackReport>>),
+    #[error("{}", .0.node)]
+    Codegen(Spanned<CodegenError>),
+    #[error("{0}")]
+    Io(String),
+    #[error("`{0}` failed to compile the generated C")]
+    CCompile(String),
+    #[error("{} files are not formatted", .0.len())]
+    Unformatted(Vec<PathBuf>),
+}
+
+impl From<Spanned<CodegenError>> for CarpnError {
+    fn from(e: Spanned<CodegenError>) -> Self {
+        CarpnError::Codegen(e)
+    }
+}
+
+impl CarpnError {
+    pub fn io(path: &Path, e: io::Error) -> Self {
+        CarpnError::Io(format!("{}: {}", path.display(), e))
+    }
+
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            CarpnError::Lex(errors) => errors
+                .iter()
+                .map(|e| {
+                    Diagnostic::new(e.node.to_string())
+                        .at(e.span.clone())
+                        .help(e.node.help())
+                })
+                .collect(),
+            CarpnError::Parse(errors) => errors
+                .iter()
+                .map(|e| {
+                    Diagnostic::new(e.node.to_string())
+                        .at(e.span.clone())
+                        .help(e.node.help())
+                })
+                .collect(),
+            CarpnError::Check(reports) => reports
+                .iter()
+                .map(|e| {
+                    let mut d = Diagnostic::new(e.node.error.to_string()).at(e.span.clone());
+                    for note in &e.node.notes {
+                        d = d.note(note);
+                    }
+                    d.help(e.node.error.help())
+                })
+                .collect(),
+            CarpnError::Codegen(e) => vec![Diagnostic::new(e.node.to_string())
+                .at(e.span.clone())
+                .help(e.node.help())],
+            CarpnError::Io(_) | CarpnError::CCompile(_) => vec![Diagnostic::new(self.to_string())],
+            CarpnError::Unformatted(files) => files
+                .iter()
+                .map(|f| {
+                    Diagnostic::new(format!("`{}` is not formatted", f.display()))
+                        .help(Some("run `carpn2 fmt` to format it"))
+                })
+                .collect(),
+        }
+    }
 }
 
 #[derive(Error, Debug)]
@@ -20,6 +92,306 @@ pub enum ParseError {
     MissingCloseCurly,
     #[error("missing struct name")]
     MissingStructName,
+    #[error("missing def name")]
+    MissingDefName,
+    #[error("missing include path")]
+    MissingIncludePath,
+    #[error("expected a top level item")]
+    ExpectedItem,
+    #[error("unexpected `{0}`")]
+    UnexpectedToken(String),
+    #[error("missing name after `=>`")]
+    MissingBindingName,
+    #[error("missing type for parameter `{0}`")]
+    MissingParamType(String),
+    #[error("parameter `{0}` is named twice")]
+    DuplicateParam(String),
     #[error("unreachable")]
     Unreachable,
 }
+
+impl ParseError {
+    pub fn help(&self) -> Option<&'static str> {
+        match self {
+            ParseError::PrototypeMissingName => {
+                Some("the name comes right after `proc` or `extern`, as in `proc main : i32 { 0 }`")
+            }
+            ParseError::MissingBody => Some("a body is a block of words in `{ ... }`"),
+            ParseError::MissingCloseCurly => Some("every `{` needs a matching `}`"),
+            ParseError::MissingStructName => Some("a struct is written `struct Name { ... }`"),
+            ParseError::MissingDefName => Some("a def is written `def name { ... }`"),
+            ParseError::MissingIncludePath => {
+                Some("the path is a string, as in `inc \"lib.car\"` or `cinc \"<stdio.h>\"`")
+            }
+            ParseError::MissingBindingName => Some("a binding is written `=> name`"),
+            ParseError::MissingParamType(_) => {
+                Some("a named parameter is written `name:type` with no spaces, as in `a:i32`")
+            }
+            ParseError::ExpectedItem => {
+                Some("only `proc`, `extern`, `struct`, `def`, `inc` and `cinc` can appear here")
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum CodegenError {
+    #[error("`{word}` expected a value, found {found}")]
+    ExpectedValueFoundType { word: String, found: String },
+    #[error("`{word}` expected a type, found {found}")]
+    ExpectedTypeFoundValue { word: String, found: String },
+    #[error("`{word}` takes its count from an integer literal, found `{found}`")]
+    BuiltinCount { word: String, found: String },
+    #[error("unknown identifier `{0}`")]
+    UnknownIdentifier(String),
+    #[error("`{name}` is out of scope here, it was declared in a {kind} at {declared}")]
+    OutOfScope {
+        name: String,
+        kind: String,
+        declared: String,
+    },
+    #[error("no field `{field}` on {found}")]
+    UnknownField { field: String, found: String },
+    #[error("proc `{0}` has a `:` but no result types")]
+    EmptyResult(String),
+    #[error("extern `{name}` can return at most one type, found {count}")]
+    ExternResults { name: String, count: usize },
+    #[error("parameter `{name}` must have exactly one type, found {count}")]
+    ParamCount { name: String, count: usize },
+    #[error("cannot mix `{a}` and `{b}` in {op}")]
+    MixedNumbers { op: String, a: String, b: String },
+    #[error("{op} needs integer operands, found `{a}` and `{b}`")]
+    IntegerOperands { op: String, a: String, b: String },
+    #[error("`{name}` is a builtin word and can't name a {what}")]
+    BuiltinName { name: String, what: String },
+    #[error("def `{0}` refers to itself")]
+    RecursiveDef(String),
+    #[error("{what} replaced the value `{value}` on the stack")]
+    LoopReplacedValue { what: String, value: String },
+    #[error("while condition must not change the values below it")]
+    ConditionChangedStack,
+    #[error("if branches leave different types on the stack: `{0}` and `{1}`")]
+    BranchTypes(String, String),
+    #[error("if branches disagree about the value `{0}` on the stack")]
+    BranchValue(String),
+    #[error("if branches leave different entries on the stack: {0} and {1}")]
+    BranchEntries(String, String),
+    #[error("`{word}` needs {needed} entries, but the stack holds {found}")]
+    Underflow {
+        word: String,
+        needed: usize,
+        found: usize,
+    },
+    #[error("proc `{name}` has the stack effect {found}, but its prototype declares {declared}")]
+    EffectMismatch {
+        name: String,
+        found: String,
+        declared: String,
+    },
+    #[error("if branches leave different stack depths: {0} entries and {1} entries")]
+    BranchDepth(usize, usize),
+    #[error("while condition must leave {expected} entries on the stack, found {found}")]
+    ConditionDepth { expected: usize, found: usize },
+    #[error("{what} must leave the stack as deep as it found it: expected {expected} entries, found {found}")]
+    LoopDepth {
+        what: String,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl CodegenError {
+    pub fn help(&self) -> Option<&'static str> {
+        match self {
+            CodegenError::MixedNumbers { .. } => Some("cast one side with `'` first"),
+            CodegenError::OutOfScope { .. } => Some(
+                "a name lasts until the end of the block it was declared in, bind it before the block to use it after",
+            ),
+            CodegenError::BuiltinCount { .. } => {
+                Some("write the count right before the word, as in `2 pick`")
+            }
+            CodegenError::EmptyResult(_) => Some("leave out the `:` for a proc that returns nothing"),
+            CodegenError::ExternResults { .. } => Some("a C function returns a single value"),
+            CodegenError::BuiltinName { .. } => {
+                Some("a builtin word always means the same thing, pick another name")
+            }
+            CodegenError::RecursiveDef(_) => {
+                Some("defs are inlined where they are used, use a proc for recursion")
+            }
+            CodegenError::Underflow { .. } => Some(
+                "a proc starts with the arguments its prototype declares, and nothing below them",
+            ),
+            CodegenError::EffectMismatch { .. } => {
+                Some("a proc must end with exactly its results on the stack; `disc` drops the rest")
+            }
+            CodegenError::BranchDepth(..) => {
+                Some("both arms of an `if` must push and pop the same number of entries")
+            }
+            CodegenError::ConditionDepth { .. } => {
+                Some("a condition pushes one value for `while` to test and leaves the rest alone")
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A problem found by the stack checker, with notes showing the stack
+/// where it was found.
+#[derive(Debug)]
+pub struct StackReport {
+    pub error: CodegenError,
+    pub notes: Vec<String>,
+}
+
+#[derive(Error, Debug, PartialEq)]
+pub enum LexError {
+    #[error("invalid token `{0}`")]
+    InvalidToken(String),
+    #[error("invalid number literal `{0}`")]
+    InvalidNumber(String),
+    #[error("invalid number literal `{literal}`: unknown suffix `{suffix}`")]
+    InvalidSuffix {
+        literal: String,
+        suffix: String,
+        at: Range<usize>,
+    },
+    #[error("invalid number literal `{literal}`: `{digit}` is not a base {radix} digit")]
+    InvalidDigit {
+        literal: String,
+        digit: char,
+        radix: u32,
+        at: Range<usize>,
+    },
+    #[error("`{literal}` does not fit in `{kind}`")]
+    NumberOutOfRange { literal: String, kind: String },
+    #[error("unterminated string")]
+    UnterminatedString,
+    #[error("unterminated block comment")]
+    UnterminatedComment,
+    #[error("unterminated character literal")]
+    UnterminatedChar,
+    #[error("invalid escape `{0}`")]
+    InvalidEscape(String),
+    #[error("character literal `{0}` must hold exactly one character")]
+    CharLength(String),
+    #[error("character literal `{0}` is not ascii")]
+    NonAsciiChar(String),
+}
+
+impl LexError {
+    pub fn help(&self) -> Option<&'static str> {
+        match self {
+            LexError::NumberOutOfRange { kind, .. } => match kind.as_str() {
+                "i64" | "u64" | "f64" => None,
+                "f32" => Some("a `f64` suffix, as in `1e300f64`, gives a literal a wider type"),
+                _ => Some("a type suffix such as `10i64` gives a literal a wider type"),
+            },
+            LexError::InvalidSuffix { .. } => Some(
+                "the suffixes are `i8`, `i16`, `i32`, `i64`, `u8`, `u16`, `u32`, `u64`, `f32` and `f64`",
+            ),
+            LexError::UnterminatedString => {
+                Some("a string ends at the next `\"`, write `\\\"` for a quote inside one")
+            }
+            LexError::UnterminatedChar => {
+                Some("a character literal is one character between ticks, as in `'a'` or `'\\n'`")
+            }
+            LexError::InvalidEscape(_) => Some(
+                "the escapes are `\\n`, `\\t`, `\\r`, `\\0`, `\\\\`, `\\\"`, `\\'`, `\\xNN` and `\\u{NNNN}`",
+            ),
+            LexError::UnterminatedComment => Some("every `#(` needs a matching `)#`"),
+            LexError::CharLength(_) => Some("use a string for more than one character"),
+            LexError::NonAsciiChar(_) => Some("use a string for characters outside ascii"),
+            _ => None,
+        }
+    }
+
+    /// The bytes of the token the error is about, when it is only part of
+    /// it.
+    pub fn within(&self) -> Option<Range<usize>> {
+        match self {
+            LexError::InvalidSuffix { at, .. } | LexError::InvalidDigit { at, .. } => {
+                Some(at.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A report for the user, pointing at the source that caused it.
+#[derive(Debug, Serialize)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Option<Span>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>) -> Self {
+        Diagnostic {
+            message: message.into(),
+            span: None,
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn at(mut self, span: Span) -> Self {
+        self.span = Some(span);
+        self
+    }
+
+    pub fn note(mut self, note: &str) -> Self {
+        self.notes.push(format!("note: {}", note));
+        self
+    }
+
+    pub fn help(mut self, help: Option<&str>) -> Self {
+        if let Some(help) = help {
+            self.notes.push(format!("help: {}", help));
+        }
+        self
+    }
+
+    /// Renders the message, the offending line with a caret under the span,
+    /// and any notes.
+    pub fn render(&self) -> String {
+        let mut result = format!("error: {}\n", self.message);
+        let gutter = match &self.span {
+            Some(span) => " ".repeat(span.line.to_string().len()),
+            None => " ".to_string(),
+        };
+
+        if let Some(span) = &self.span {
+            result += &format!("{}--> {}\n", gutter, span);
+
+            if let Ok(source) = fs::read_to_string(&*span.file) {
+                let line = source.lines().nth(span.line - 1).unwrap_or("");
+
+                // keep tabs so the caret lines up however they are shown
+                let pad: String = line
+                    .chars()
+                    .take(span.column - 1)
+                    .map(|c| if c == '\t' { '\t' } else { ' ' })
+                    .collect();
+
+                let width = source
+                    .get(span.start..span.end)
+                    .unwrap_or("")
+                    .chars()
+                    .take_while(|c| *c != '\n')
+                    .count()
+                    .max(1);
+
+                result += &format!("{} |\n", gutter);
+                result += &format!("{} | {}\n", span.line, line);
+                result += &format!("{} | {}{}\n", gutter, pad, "^".repeat(width));
+            }
+        }
+
+        for note in &self.notes {
+            result += &format!("{} = {}\n", gutter, note);
+        }
+
+        result
+    }
+}
@@ -0,0 +1,465 @@
+//! The canonical layout of `.car` files, as written by `carpn2 fmt`.
+//!
+//! Where lines break comes from the structure of the file rather than from
+//! where the author broke them:
+//!
+//! - every item starts a line, and two items are a blank line apart unless
+//!   both are includes, both externs or both defs,
+//! - the body of a struct always spans lines,
+//! - any other block stays on one line if it fits in 80 columns and holds
+//!   no other block, line comment or end of a statement; otherwise its `{`
+//!   ends a line and its `}` starts one,
+//! - in a block that spans lines, a statement ends its line: an `if`, a
+//!   `while` or a `loop` with its blocks, a `ret` or an `=> name`. Other
+//!   words fill the line and wrap before the one that would pass 80 columns,
+//! - lines are indented four spaces for each block they are in,
+//! - words are one space apart, unless they were written together as in
+//!   `i8$*` or `Math.quad`,
+//! - a blank line between two statements or items is kept, as one,
+//! - a comment stays at the end of the line it was on, or on a line of its
+//!   own if it had one.
+
+use crate::lexer::{Lexer, Token};
+use crate::parser::*;
+use crate::span::{Span, Spanned};
+use crate::walk::{walk_expr, walk_file, walk_struct, Visit};
+use std::collections::{HashMap, HashSet};
+
+const WIDTH: usize = 80;
+
+/// Lays out the source of `file` read by `lex`, which must be the file the
+/// parser read.
+pub fn format(lex: Lexer, file: &CarpnFile) -> String {
+    let src = lex.source();
+    let tokens: Vec<Spanned<Token>> = lex.flatten().collect();
+
+    let mut structure = Structure::default();
+    structure.visit_file(file);
+
+    let mut f = Formatter {
+        src: &src,
+        tokens: &tokens,
+        closes: HashMap::new(),
+        item_starts: HashSet::new(),
+        blank_before: HashSet::new(),
+        struct_bodies: HashSet::new(),
+        statement_ends: structure.statement_ends,
+        multiline: HashSet::new(),
+        out: "".to_string(),
+        line: "".to_string(),
+        blocks: Vec::new(),
+        break_next: false,
+    };
+    f.resolve(&structure.items, &structure.structs);
+    f.write();
+
+    f.out
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum ItemKind {
+    Include,
+    Extern,
+    Def,
+    Proc,
+    Struct,
+}
+
+/// What the formatter needs from the syntax tree, by byte offset.
+#[derive(Default)]
+struct Structure {
+    /// each list of items side by side in a file or struct, in source order
+    items: Vec<Vec<(Span, ItemKind)>>,
+    structs: Vec<usize>,
+    statement_ends: HashSet<usize>,
+}
+
+/// The members of a file or struct, in source order.
+fn members(defs: &[Def], procs: &[Proc], structs: &[Struct]) -> Vec<(Span, ItemKind)> {
+    let mut items: Vec<_> = defs
+        .iter()
+        .map(|d| (d.span.clone(), ItemKind::Def))
+        .collect();
+    items.extend(procs.iter().map(|p| {
+        let kind = match p.body {
+            ProcBody::Extern => ItemKind::Extern,
+            ProcBody::Impl(_) => ItemKind::Proc,
+        };
+        (p.span.clone(), kind)
+    }));
+    items.extend(structs.iter().map(|s| (s.span.clone(), ItemKind::Struct)));
+
+    items
+}
+
+impl Visit for Structure {
+    fn visit_file(&mut self, file: &CarpnFile) {
+        let mut items: Vec<_> = file
+            .includes
+            .iter()
+            .map(|i| (i.span.clone(), ItemKind::Include))
+            .collect();
+        items.extend(members(&file.defs, &file.procs, &file.structs));
+        items.sort_by_key(|(s, _)| s.start);
+        self.items.push(items);
+
+        walk_file(self, file);
+    }
+
+    fn visit_struct(&mut self, s: &Struct) {
+        let mut items = members(&s.defs, &s.procs, &s.structs);
+        items.sort_by_key(|(s, _)| s.start);
+        self.items.push(items);
+        self.structs.push(s.span.start);
+
+        walk_struct(self, s);
+    }
+
+    fn visit_expr(&mut self, expr: &Spanned<Expression>) {
+        if matches!(
+            expr.node,
+            Expression::If(_)
+                | Expression::While(_)
+                | Expression::Loop(_)
+                | Expression::Return
+                | Expression::Op(ExprOp::Name(_))
+        ) {
+            self.statement_ends.insert(expr.span.end);
+        }
+
+        walk_expr(self, expr);
+    }
+}
+
+struct Formatter<'a> {
+    src: &'a str,
+    tokens: &'a [Spanned<Token>],
+    /// the `}` closing each `{`, by token
+    closes: HashMap<usize, usize>,
+    /// tokens that start an item
+    item_starts: HashSet<usize>,
+    /// tokens with a blank line before them
+    blank_before: HashSet<usize>,
+    /// the `{` of each struct
+    struct_bodies: HashSet<usize>,
+    /// byte offsets that statements end at
+    statement_ends: HashSet<usize>,
+    /// blocks that span lines, by their `{` and by their `}`
+    multiline: HashSet<usize>,
+    out: String,
+    /// the line being written
+    line: String,
+    /// whether each block the writer is in spans lines
+    blocks: Vec<bool>,
+    break_next: bool,
+}
+
+impl<'a> Formatter<'a> {
+    fn end_line(&self, span: &Span) -> usize {
+        span.line + self.src[span.start..span.end].matches('\n').count()
+    }
+
+    /// The first token at or after the byte offset `start`.
+    fn token_at(&self, start: usize) -> usize {
+        self.tokens.partition_point(|t| t.span.start < start)
+    }
+
+    fn text(&self, i: usize) -> &'a str {
+        let span = &self.tokens[i].span;
+        self.src[span.start..span.end].trim_end()
+    }
+
+    /// Works out the token indices of what the syntax tree gave by offset.
+    fn resolve(&mut self, items: &[Vec<(Span, ItemKind)>], structs: &[usize]) {
+        let mut opens = Vec::new();
+        for (i, t) in self.tokens.iter().enumerate() {
+            match t.node {
+                Token::BlockOpen(_) => opens.push(i),
+                Token::BlockClose(_) => {
+                    if let Some(open) = opens.pop() {
+                        self.closes.insert(open, i);
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        for start in structs {
+            let open = (self.token_at(*start)..self.tokens.len())
+                .find(|i| matches!(self.tokens[*i].node, Token::BlockOpen(_)));
+            self.struct_bodies.extend(open);
+        }
+
+        for items in items {
+            for (item, _) in items {
+                self.item_starts.insert(self.token_at(item.start));
+            }
+
+            for pair in items.windows(2) {
+                let ((a, a_kind), (_, b_kind)) = (&pair[0], &pair[1]);
+                let grouped =
+                    matches!(a_kind, ItemKind::Include | ItemKind::Extern | ItemKind::Def);
+                if grouped && a_kind == b_kind {
+                    continue;
+                }
+
+                // the blank goes above any comments on the item, but below
+                // one at the end of the item before
+                let end_line = self.end_line(a);
+                let first = (self.token_at(a.end)..self.tokens.len()).find(|i| {
+                    let t = &self.tokens[*i];
+                    !matches!(t.node, Token::Comment(_)) || t.span.line > end_line
+                });
+                self.blank_before.extend(first);
+            }
+        }
+    }
+
+    /// Whether nothing goes between tokens `a` and `b`.
+    fn joined(&self, a: usize, b: usize) -> bool {
+        let (a, b) = (&self.tokens[a], &self.tokens[b]);
+        let trivia = |t: &Token| matches!(t, Token::Comment(_) | Token::Doc(_));
+        let brace = |t: &Token| matches!(t, Token::BlockOpen(_) | Token::BlockClose(_));
+
+        let glued = b.span.start == a.span.end
+            && !trivia(&a.node)
+            && !brace(&a.node)
+            && !trivia(&b.node)
+            && !brace(&b.node);
+        let empty_block =
+            matches!(a.node, Token::BlockOpen(_)) && matches!(b.node, Token::BlockClose(_));
+
+        glued || empty_block
+    }
+
+    /// The width of token `i` along with the tokens glued on after it, which
+    /// can't be split across lines.
+    fn word_width(&self, i: usize) -> usize {
+        let end = (i + 1..self.tokens.len())
+            .find(|j| !self.joined(j - 1, *j))
+            .unwrap_or(self.tokens.len());
+
+        (i..end).map(|j| self.text(j).len()).sum()
+    }
+
+    /// Whether the line must end after token `i` because it is a comment.
+    fn ends_line(&self, i: usize) -> bool {
+        match &self.tokens[i].node {
+            Token::Doc(_) => true,
+            Token::Comment(c) => !c.starts_with("#(") || c.contains('\n'),
+            _ => false,
+        }
+    }
+
+    fn ends_statement(&self, i: usize) -> bool {
+        self.statement_ends.contains(&self.tokens[i].span.end)
+    }
+
+    /// Whether a blank line was written between tokens `a` and `b`.
+    fn blank_between(&self, a: usize, b: usize) -> bool {
+        self.tokens[b].span.line > self.end_line(&self.tokens[a].span) + 1
+    }
+
+    /// Whether the block opened by token `open` can be written on the rest
+    /// of the line being written.
+    fn fits_flat(&self, open: usize, close: usize) -> bool {
+        if self.struct_bodies.contains(&open) && close > open + 1 {
+            return false;
+        }
+
+        for i in open + 1..close {
+            let nested = matches!(self.tokens[i].node, Token::BlockOpen(_));
+            let statement = i + 1 < close && self.ends_statement(i);
+            let blank = i > open + 1 && self.blank_between(i - 1, i);
+
+            if nested || statement || blank || self.ends_line(i) {
+                return false;
+            }
+        }
+
+        let width: usize = (open + 1..=close)
+            .map(|i| self.text(i).len() + usize::from(!self.joined(i - 1, i)))
+            .sum();
+
+        self.line.len() + width <= WIDTH
+    }
+
+    fn write(&mut self) {
+        for i in 0..self.tokens.len() {
+            let t = &self.tokens[i];
+            let open = matches!(t.node, Token::BlockOpen(_));
+            let close = matches!(t.node, Token::BlockClose(_));
+
+            if close {
+                self.blocks.pop();
+            }
+
+            if i == 0 {
+                self.line = "    ".repeat(self.blocks.len());
+            } else if self.new_line(i) {
+                self.end_line_out();
+
+                let after_open = matches!(self.tokens[i - 1].node, Token::BlockOpen(_));
+                let blank = self.blank_before.contains(&i) || self.blank_between(i - 1, i);
+                if blank && !after_open && !close {
+                    self.out.push('\n');
+                }
+
+                self.line = "    ".repeat(self.blocks.len());
+            } else if !self.joined(i - 1, i) {
+                self.line.push(' ');
+            }
+
+            self.line += self.text(i);
+            self.break_next = self.ends_line(i);
+
+            if open {
+                let close = self.closes.get(&i).copied();
+                let multiline = close.is_some_and(|close| !self.fits_flat(i, close));
+
+                if multiline {
+                    self.multiline.insert(i);
+                    self.multiline.extend(close);
+                    self.break_next = true;
+                }
+                self.blocks.push(multiline);
+            }
+        }
+
+        self.end_line_out();
+    }
+
+    /// Whether token `i` starts a line.
+    fn new_line(&self, i: usize) -> bool {
+        let t = &self.tokens[i];
+        let prev = i - 1;
+        let own_line = t.span.line > self.end_line(&self.tokens[prev].span);
+
+        match &t.node {
+            Token::Doc(_) => return true,
+            Token::Comment(_) => return own_line,
+            _ => {}
+        }
+
+        if self.break_next || self.item_starts.contains(&i) || self.multiline.contains(&i) {
+            return true;
+        }
+
+        let flat_close = matches!(t.node, Token::BlockClose(_));
+        let in_body = self.blocks.last() == Some(&true);
+        if !flat_close && self.ends_statement(prev) {
+            return true;
+        }
+        if in_body && !flat_close && self.blank_between(prev, i) {
+            return true;
+        }
+
+        let in_flat = self.blocks.last() == Some(&false);
+        !in_flat
+            && !self.joined(prev, i)
+            && !self.line.trim().is_empty()
+            && self.line.len() + 1 + self.word_width(i) > WIDTH
+    }
+
+    fn end_line_out(&mut self) {
+        if !self.line.trim().is_empty() {
+            self.out += self.line.trim_end();
+            self.out.push('\n');
+        }
+        self.line.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn fmt(src: &str) -> String {
+        let lex = Lexer::with_source(PathBuf::from("test.car"), src);
+        let file = CarpnFile::parse(&mut Tokens::new(lex.clone()).unwrap()).unwrap();
+
+        format(lex, &file)
+    }
+
+    const MESSY: &str = r#"cinc "<stdio.h>" # stdio
+extern printf i8$* i32
+  extern puts i8 $ * : i32
+## Doubles it.
+def double { copy
+  + }
+struct Math { proc quad i32 : i32 { Self.double
+        Self.double } proc half i32 : i32 { 2 / } }
+proc main : i32 {
+    "%d\n" 3 Math.quad
+    printf # trailing
+    #( block
+     comment )#
+    1 => x x if { "%d\n" 1 printf } else { 2 => y "%d\n" y printf }
+
+
+    0 while { copy 3 < } { 1 + } disc "%d %d %d %d %d %d %d %d\n" 1 2 3 4 5 6 7 8 printf 0
+}
+proc empty : void {   }"#;
+
+    #[test]
+    fn layout_comes_from_structure() {
+        let expected = r#"cinc "<stdio.h>" # stdio
+
+extern printf i8$* i32
+extern puts i8 $ * : i32
+
+## Doubles it.
+def double { copy + }
+
+struct Math {
+    proc quad i32 : i32 { Self.double Self.double }
+
+    proc half i32 : i32 { 2 / }
+}
+
+proc main : i32 {
+    "%d\n" 3 Math.quad printf # trailing
+    #( block
+     comment )#
+    1 => x
+    x if { "%d\n" 1 printf } else {
+        2 => y
+        "%d\n" y printf
+    }
+
+    0 while { copy 3 < } { 1 + }
+    disc "%d %d %d %d %d %d %d %d\n" 1 2 3 4 5 6 7 8 printf 0
+}
+
+proc empty : void {}
+"#;
+
+        assert_eq!(fmt(MESSY), expected);
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        let sources = [
+            MESSY,
+            include_str!("../tests/test.car"),
+            include_str!("../tests/imp.car"),
+        ];
+
+        for src in sources {
+            let once = fmt(src);
+            assert_eq!(fmt(&once), once);
+        }
+    }
+
+    #[test]
+    fn long_lines_wrap_between_words() {
+        let words = ["Math.quad"; 12].join(" ");
+        let out = fmt(&format!("proc f i32 : i32 {{ {} }}", words));
+
+        for line in out.lines() {
+            assert!(line.len() <= WIDTH, "{:?}", line);
+        }
+        assert_eq!(fmt(&out), out);
+    }
+}
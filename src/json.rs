@@ -0,0 +1,84 @@
+//! The JSON form of a parsed file, for editors and other tools.
+//!
+//! `carpn2 parse --format json` writes one document per input file, each on
+//! its own line:
+//!
+//! ```text
+//! {
+//!   "version": 1,
+//!   "file": "main.car",
+//!   "tokens": [Spanned<Token>],
+//!   "ast": CarpnFile | null,
+//!   "errors": [Diagnostic]
+//! }
+//! ```
+//!
+//! `version` is bumped whenever a field is renamed or removed, so tools can
+//! refuse documents they do not understand. New fields may appear without a
+//! bump.
+//!
+//! `ast` is `null` when the file has errors, `tokens` then holds the tokens
+//! that did lex.
+//!
+//! The parts of the document are:
+//!
+//! - `Spanned<T>` is `{ "node": T, "span": Span }`.
+//! - `Span` is `{ "line", "column", "start", "end" }`. Lines and columns
+//!   count from one, `start` and `end` are byte offsets into `file`.
+//! - `Token` is `{ "kind": "...", "value": ... }`. Keywords have no value
+//!   (`{ "kind": "proc" }`), `block_open` and `block_close` hold `"curly"`
+//!   or `"bracket"`, `op` holds the operator as written, `int` and `float`
+//!   hold `[number, suffix]` where the suffix is a type name such as `"u8"`
+//!   or `null`, and `string`, `char`, `doc`, `comment` and `ident` hold
+//!   their text. Comments are only found in `tokens`, never in `ast`.
+//! - `Expression` is `{ "kind": "...", "value": ... }` in the same way,
+//!   with the kinds `int`, `float`, `ident`, `op`, `string`, `char`,
+//!   `prop`, `return`, `if` (`{ "body", "else_body" }`), `while`
+//!   (`{ "cond", "body" }`), `loop` (`{ "body" }`) and `param`
+//!   (`{ "name", "kind" }`, only found in `Prototype` args). Bodies are
+//!   lists of `Spanned<Expression>`. A binding is the op `"=> name"`.
+//! - `CarpnFile` is `{ "includes", "defs", "procs", "structs" }`.
+//! - `Include` is `{ "file", "is_c", "span" }`.
+//! - `Def` is `{ "doc", "name", "body", "span" }`.
+//! - `Proc` is `{ "doc", "def": Prototype, "body", "span" }` where `body`
+//!   is `{ "kind": "extern" }` or `{ "kind": "impl", "body": [...] }`.
+//! - `Prototype` is `{ "name", "span", "args", "result" }`, `result` is
+//!   `null` when the proc has no `:`.
+//! - `Struct` is `{ "doc", "name", "defs", "procs", "structs", "body",
+//!   "span" }`, where `body` holds the field declarations.
+//! - `Diagnostic` is `{ "message", "span", "notes" }`.
+
+use crate::error::{CarpnError, Diagnostic};
+use crate::lexer::{Lexer, Token};
+use crate::parser::CarpnFile;
+use crate::span::Spanned;
+use serde::Serialize;
+use std::path::Path;
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+#[derive(Serialize)]
+struct Document<'a> {
+    version: u32,
+    file: String,
+    tokens: Vec<Spanned<Token>>,
+    ast: Option<&'a CarpnFile>,
+    errors: Vec<Diagnostic>,
+}
+
+/// The document for `file`, given the lexer over it and what parsing it
+/// gave.
+pub fn document(file: &Path, lex: Lexer, parsed: &Result<CarpnFile, CarpnError>) -> String {
+    let doc = Document {
+        version: SCHEMA_VERSION,
+        file: file.display().to_string(),
+        tokens: lex.flatten().collect(),
+        ast: parsed.as_ref().ok(),
+        errors: match parsed {
+            Ok(_) => Vec::new(),
+            Err(e) => e.diagnostics(),
+        },
+    };
+
+    serde_json::to_string(&doc).expect("the syntax tree always serialises")
+}
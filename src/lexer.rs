@@ -1,7 +1,10 @@
-use std::fs::File;
-use std::io::BufReader;
-use std::io::Read;
+use crate::error::{CarpnError, LexError};
+use crate::span::{Span, Spanned};
+use serde::{Serialize, Serializer};
+use std::fmt;
+use std::fs;
 use std::path::PathBuf;
+use std::rc::Rc;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Operation {
@@ -16,15 +19,157 @@ pub enum Operation {
     Lt,
     Gt,
     At,
+    Slash,
+    Percent,
+    Amp,
+    Pipe,
+    Caret,
+    Shl,
+    Shr,
+    Le,
+    Ge,
+    Ne,
+    And,
+    Or,
+    Not,
+    Neg,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+impl Serialize for Operation {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Operation::Dollar => "$",
+            Operation::Colon => ":",
+            Operation::Minus => "-",
+            Operation::Equal => "=",
+            Operation::Plus => "+",
+            Operation::Star => "*",
+            Operation::Tick => "'",
+            Operation::Dot => ".",
+            Operation::Lt => "<",
+            Operation::Gt => ">",
+            Operation::At => "@",
+            Operation::Slash => "/",
+            Operation::Percent => "%",
+            Operation::Amp => "&",
+            Operation::Pipe => "|",
+            Operation::Caret => "^",
+            Operation::Shl => "<<",
+            Operation::Shr => ">>",
+            Operation::Le => "<=",
+            Operation::Ge => ">=",
+            Operation::Ne => "!=",
+            Operation::And => "and",
+            Operation::Or => "or",
+            Operation::Not => "not",
+            Operation::Neg => "neg",
+        };
+
+        write!(f, "{}", s)
+    }
+}
+
+/// The type suffix on a number literal, as in `10u8` or `1.5f32`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum NumSuffix {
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    F32,
+    F64,
+}
+
+impl Serialize for NumSuffix {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl NumSuffix {
+    pub const ALL: [NumSuffix; 10] = [
+        NumSuffix::I8,
+        NumSuffix::I16,
+        NumSuffix::I32,
+        NumSuffix::I64,
+        NumSuffix::U8,
+        NumSuffix::U16,
+        NumSuffix::U32,
+        NumSuffix::U64,
+        NumSuffix::F32,
+        NumSuffix::F64,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            NumSuffix::I8 => "i8",
+            NumSuffix::I16 => "i16",
+            NumSuffix::I32 => "i32",
+            NumSuffix::I64 => "i64",
+            NumSuffix::U8 => "u8",
+            NumSuffix::U16 => "u16",
+            NumSuffix::U32 => "u32",
+            NumSuffix::U64 => "u64",
+            NumSuffix::F32 => "f32",
+            NumSuffix::F64 => "f64",
+        }
+    }
+
+    /// The C type the suffix names.
+    pub fn c_type(&self) -> &'static str {
+        match self {
+            NumSuffix::I8 => "char",
+            NumSuffix::I16 => "short",
+            NumSuffix::I32 => "int",
+            NumSuffix::I64 => "long long",
+            NumSuffix::U8 => "unsigned char",
+            NumSuffix::U16 => "unsigned short",
+            NumSuffix::U32 => "unsigned int",
+            NumSuffix::U64 => "unsigned long long",
+            NumSuffix::F32 => "float",
+            NumSuffix::F64 => "double",
+        }
+    }
+
+    pub fn is_float(&self) -> bool {
+        matches!(self, NumSuffix::F32 | NumSuffix::F64)
+    }
+
+    /// The smallest and largest integer the type holds.
+    fn range(&self) -> (i128, i128) {
+        match self {
+            NumSuffix::I8 => (i8::MIN.into(), i8::MAX.into()),
+            NumSuffix::I16 => (i16::MIN.into(), i16::MAX.into()),
+            NumSuffix::I32 => (i32::MIN.into(), i32::MAX.into()),
+            NumSuffix::I64 => (i64::MIN.into(), i64::MAX.into()),
+            NumSuffix::U8 => (0, u8::MAX.into()),
+            NumSuffix::U16 => (0, u16::MAX.into()),
+            NumSuffix::U32 => (0, u32::MAX.into()),
+            NumSuffix::U64 => (0, u64::MAX.into()),
+            NumSuffix::F32 | NumSuffix::F64 => (i128::MIN, i128::MAX),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(rename_all = "snake_case")]
 pub enum BlockKind {
     Curly,
     Bracket,
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
 pub enum Token {
     CInclude,
     Include,
@@ -34,19 +179,75 @@ pub enum Token {
     Def,
     Ret,
     If,
+    Else,
+    While,
+    Loop,
     BlockOpen(BlockKind),
     BlockClose(BlockKind),
     Op(Operation),
-    Int(i64),
-    Float(f64),
+    Int(i128, Option<NumSuffix>),
+    Float(f64, Option<NumSuffix>),
     String(String),
+    Char(char),
+    /// A `##` comment, documenting the item after it.
+    Doc(String),
+    /// A `#` or `#( )#` comment, as written.
+    Comment(String),
 
     Ident(String),
 }
 
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Token::CInclude => write!(f, "cinc"),
+            Token::Include => write!(f, "inc"),
+            Token::Extern => write!(f, "extern"),
+            Token::Struct => write!(f, "struct"),
+            Token::Proc => write!(f, "proc"),
+            Token::Def => write!(f, "def"),
+            Token::Ret => write!(f, "ret"),
+            Token::If => write!(f, "if"),
+            Token::Else => write!(f, "else"),
+            Token::While => write!(f, "while"),
+            Token::Loop => write!(f, "loop"),
+            Token::BlockOpen(BlockKind::Curly) => write!(f, "{{"),
+            Token::BlockOpen(BlockKind::Bracket) => write!(f, "["),
+            Token::BlockClose(BlockKind::Curly) => write!(f, "}}"),
+            Token::BlockClose(BlockKind::Bracket) => write!(f, "]"),
+            Token::Op(op) => write!(f, "{}", op),
+            Token::Int(i, suffix) => {
+                write!(f, "{}{}", i, suffix.map(|s| s.name()).unwrap_or(""))
+            }
+            Token::Float(x, suffix) => {
+                write!(f, "{:?}{}", x, suffix.map(|s| s.name()).unwrap_or(""))
+            }
+            Token::String(s) => write!(f, "\"{}\"", s.escape_debug()),
+            Token::Char(c) => write!(f, "'{}'", c.escape_debug()),
+            Token::Doc(s) => write!(f, "## {}", s),
+            Token::Comment(s) => write!(f, "{}", s),
+            Token::Ident(s) => write!(f, "{}", s),
+        }
+    }
+}
+
 impl Token {
-    pub fn new(base: &String) -> Result<Self, String> {
-        match base.as_str() {
+    /// Whether the token starts a top level item, where the parser picks
+    /// up again after an error.
+    pub fn starts_item(&self) -> bool {
+        matches!(
+            self,
+            Token::Proc
+                | Token::Extern
+                | Token::Struct
+                | Token::Def
+                | Token::Include
+                | Token::CInclude
+        )
+    }
+
+    pub fn new(base: &str) -> Result<Self, LexError> {
+        match base {
             "extern" => Ok(Self::Extern),
             "struct" => Ok(Self::Struct),
             "cinc" => Ok(Self::CInclude),
@@ -55,6 +256,9 @@ impl Token {
             "def" => Ok(Self::Def),
             "ret" => Ok(Self::Ret),
             "if" => Ok(Self::If),
+            "else" => Ok(Self::Else),
+            "while" => Ok(Self::While),
+            "loop" => Ok(Self::Loop),
             "$" => Ok(Self::Op(Operation::Dollar)),
             ":" => Ok(Self::Op(Operation::Colon)),
             "=" => Ok(Self::Op(Operation::Equal)),
@@ -66,51 +270,436 @@ impl Token {
             "<" => Ok(Self::Op(Operation::Lt)),
             ">" => Ok(Self::Op(Operation::Gt)),
             "@" => Ok(Self::Op(Operation::At)),
+            "/" => Ok(Self::Op(Operation::Slash)),
+            "%" => Ok(Self::Op(Operation::Percent)),
+            "&" => Ok(Self::Op(Operation::Amp)),
+            "|" => Ok(Self::Op(Operation::Pipe)),
+            "^" => Ok(Self::Op(Operation::Caret)),
+            "<<" => Ok(Self::Op(Operation::Shl)),
+            ">>" => Ok(Self::Op(Operation::Shr)),
+            "<=" => Ok(Self::Op(Operation::Le)),
+            ">=" => Ok(Self::Op(Operation::Ge)),
+            "!=" => Ok(Self::Op(Operation::Ne)),
+            "and" => Ok(Self::Op(Operation::And)),
+            "or" => Ok(Self::Op(Operation::Or)),
+            "not" => Ok(Self::Op(Operation::Not)),
+            "neg" => Ok(Self::Op(Operation::Neg)),
             s => {
-                if s.starts_with('"') && s.ends_with('"') {
-                    let mut new = s.to_string();
-                    new.remove(new.len() - 1);
-                    new.remove(0);
-                    return Ok(Self::String(new));
-                } else {
-                    let dot = s.chars().filter(|c| *c == '.').count() == 1;
-                    let too_many = s.chars().filter(|c| *c == '.').count() > 1;
-                    let number = s.chars().filter(|c| *c != '.').all(|c| c.is_numeric());
-                    let chars = s.chars().all(|c| c.is_alphanumeric());
-                    if !dot && number {
-                        Ok(Self::Int(s.parse().unwrap()))
-                    } else if dot && !too_many && number {
-                        Ok(Self::Float(s.parse().unwrap()))
-                    } else if chars {
-                        Ok(Self::Ident(base.clone()))
-                    } else {
-                        println!("Invalid Token: {}", s);
-                        Err(format!("Invalid Token: {}", s))
+                if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+                    Ok(Self::String(unescape(&s[1..s.len() - 1])?))
+                } else if let Some(raw) = raw_string(s) {
+                    Ok(Self::String(raw.to_string()))
+                } else if s.len() >= 3 && s.starts_with('\'') && s.ends_with('\'') {
+                    let body = unescape(&s[1..s.len() - 1])?;
+                    let mut chars = body.chars();
+
+                    match (chars.next(), chars.next()) {
+                        (Some(c), None) if c.is_ascii() => Ok(Self::Char(c)),
+                        (Some(_), None) => Err(LexError::NonAsciiChar(s.to_string())),
+                        _ => Err(LexError::CharLength(s.to_string())),
                     }
+                } else if let Some(number) = parse_number(s) {
+                    number
+                } else if s.chars().all(is_ident_char) {
+                    Ok(Self::Ident(base.to_string()))
+                } else {
+                    Err(LexError::InvalidToken(s.to_string()))
                 }
             }
         }
     }
 }
 
+/// Decodes the escapes in the body of a string or char literal.
+///
+/// `\n`, `\t`, `\r`, `\0`, `\\`, `\"` and `\'` stand for themselves, `\xNN`
+/// is an ascii character by its hex code and `\u{NNNN}` any unicode one. A
+/// `\` at the end of a line skips the line break and the indent after it.
+fn unescape(s: &str) -> Result<String, LexError> {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.char_indices().peekable();
+
+    while let Some((start, c)) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        let invalid = |end: usize| LexError::InvalidEscape(s[start..end].to_string());
+        let Some((i, e)) = chars.next() else {
+            return Err(invalid(s.len()));
+        };
+        let end = i + e.len_utf8();
+
+        match e {
+            'n' => result.push('\n'),
+            't' => result.push('\t'),
+            'r' => result.push('\r'),
+            '0' => result.push('\0'),
+            '\\' | '"' | '\'' => result.push(e),
+            '\n' => while chars.next_if(|(_, c)| c.is_whitespace()).is_some() {},
+            'x' => {
+                let hex = s.get(end..end + 2).ok_or_else(|| invalid(s.len()))?;
+                match u8::from_str_radix(hex, 16) {
+                    Ok(byte) if is_hex(hex) && byte.is_ascii() => result.push(byte as char),
+                    _ => return Err(invalid(end + 2)),
+                }
+                chars.nth(1);
+            }
+            'u' => {
+                let close = match s[end..].find('}') {
+                    Some(close) if s[end..].starts_with('{') => end + close,
+                    _ => return Err(invalid(end)),
+                };
+                let hex = &s[end + 1..close];
+
+                match u32::from_str_radix(hex, 16).ok().and_then(char::from_u32) {
+                    Some(c) if is_hex(hex) && hex.len() <= 6 => result.push(c),
+                    _ => return Err(invalid(close + 1)),
+                }
+                while chars.next_if(|(i, _)| *i <= close).is_some() {}
+            }
+            _ => return Err(invalid(end)),
+        }
+    }
+
+    Ok(result)
+}
+
+fn is_hex(s: &str) -> bool {
+    !s.is_empty() && s.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// The body of a raw string, written `r"..."` or `r#"..."#` with any
+/// number of `#`s so that the body can hold a `"`. Nothing in it is escaped.
+fn raw_string(s: &str) -> Option<&str> {
+    let s = s.strip_prefix('r')?;
+    let hashes = s.len() - s.trim_start_matches('#').len();
+    let body = s[hashes..].strip_prefix('"')?;
+    let body = body.strip_suffix(&s[..hashes])?;
+
+    body.strip_suffix('"')
+}
+
+/// Identifiers are made of letters, digits, `_` and the Forth style
+/// punctuation `-`, `?` and `!`, as in `is-empty?` or `2dup`. A word that
+/// reads as a number is a number first.
+fn is_ident_char(c: char) -> bool {
+    c.is_alphanumeric() || matches!(c, '_' | '-' | '?' | '!')
+}
+
+/// Reads `s` as a number literal, giving `None` when it does not look like
+/// one so that it can be tried as an identifier instead.
+///
+/// A literal is an optional `-`, then either a `0x`, `0o` or `0b` prefixed
+/// integer or a decimal with an optional fraction and exponent, then an
+/// optional type suffix. Digits may be split up with `_`. A decimal integer
+/// followed by letters, as in `2dup`, is an identifier unless the letters
+/// look like a type suffix.
+fn parse_number(s: &str) -> Option<Result<Token, LexError>> {
+    let (neg, body) = match s.strip_prefix('-') {
+        Some(body) => (true, body),
+        None => (false, s),
+    };
+
+    if !body.starts_with(|c: char| c.is_ascii_digit()) {
+        return None;
+    }
+
+    let (radix, digits) = match body.get(..2) {
+        Some("0x") => (16, &body[2..]),
+        Some("0o") => (8, &body[2..]),
+        Some("0b") => (2, &body[2..]),
+        _ => (10, body),
+    };
+
+    let is_digit = |c: u8| (c as char).is_digit(radix) || c == b'_';
+    let bytes = digits.as_bytes();
+    let skip_digits = |mut i: usize| {
+        while i < bytes.len() && is_digit(bytes[i]) {
+            i += 1;
+        }
+        i
+    };
+
+    let mut end = skip_digits(0);
+    let mut float = false;
+
+    if radix == 10 {
+        if bytes.get(end) == Some(&b'.') && bytes.get(end + 1).is_some_and(u8::is_ascii_digit) {
+            float = true;
+            end = skip_digits(end + 1);
+        }
+
+        if matches!(bytes.get(end), Some(b'e' | b'E')) {
+            let mut exp = end + 1;
+            if matches!(bytes.get(exp), Some(b'+' | b'-')) {
+                exp += 1;
+            }
+
+            if bytes.get(exp).is_some_and(u8::is_ascii_digit) {
+                float = true;
+                end = skip_digits(exp);
+            }
+        }
+    }
+
+    let (number, rest) = digits.split_at(end);
+    let at = s.len() - rest.len();
+    let suffix = match rest {
+        "" => None,
+        rest => match NumSuffix::ALL.iter().find(|s| s.name() == rest) {
+            Some(suffix) => Some(*suffix),
+            None if looks_like_suffix(rest) || (radix == 10 && float) => {
+                return Some(Err(LexError::InvalidSuffix {
+                    literal: s.to_string(),
+                    suffix: rest.to_string(),
+                    at: at..s.len(),
+                }));
+            }
+            None if radix != 10 => {
+                let digit = rest.chars().next().unwrap();
+
+                return Some(Err(LexError::InvalidDigit {
+                    literal: s.to_string(),
+                    digit,
+                    radix,
+                    at: at..at + digit.len_utf8(),
+                }));
+            }
+            None => return None,
+        },
+    };
+
+    let number = number.replace('_', "");
+    let sign = if neg { "-" } else { "" };
+    let invalid = || LexError::InvalidNumber(s.to_string());
+    let out_of_range = |kind: &str| LexError::NumberOutOfRange {
+        literal: s.to_string(),
+        kind: kind.to_string(),
+    };
+
+    if number.is_empty() {
+        return Some(Err(invalid()));
+    }
+
+    if float || suffix.is_some_and(|s| s.is_float()) {
+        if radix != 10 || suffix.is_some_and(|s| !s.is_float()) {
+            return Some(Err(invalid()));
+        }
+
+        let value: f64 = match format!("{}{}", sign, number).parse() {
+            Ok(value) => value,
+            Err(_) => return Some(Err(invalid())),
+        };
+
+        let fits = match suffix {
+            Some(NumSuffix::F32) => (value as f32).is_finite(),
+            _ => value.is_finite(),
+        };
+        if !fits {
+            return Some(Err(out_of_range(suffix.unwrap_or(NumSuffix::F64).name())));
+        }
+
+        return Some(Ok(Token::Float(value, suffix)));
+    }
+
+    let kind = suffix.unwrap_or(NumSuffix::I32);
+    let value = match i128::from_str_radix(&number, radix) {
+        Ok(value) if neg => -value,
+        Ok(value) => value,
+        Err(_) => return Some(Err(out_of_range(kind.name()))),
+    };
+
+    let (min, max) = kind.range();
+    if value < min || value > max {
+        return Some(Err(out_of_range(kind.name())));
+    }
+
+    Some(Ok(Token::Int(value, suffix)))
+}
+
+/// Whether a token ends before `c`, which is `None` at the end of the file.
+fn ends_token(c: Option<char>) -> bool {
+    c.is_none_or(|c| {
+        c.is_whitespace()
+            || WHITESPACE.contains(&c)
+            || SINGLES.contains(&c)
+            || BLOCKS.iter().any(|b| b.start == c || b.end == c)
+    })
+}
+
+/// A type letter followed by a width, as in `u7` or `i128`.
+fn looks_like_suffix(s: &str) -> bool {
+    s.len() > 1 && s.starts_with(['i', 'u', 'f']) && s[1..].bytes().all(|b| b.is_ascii_digit())
+}
+
 #[derive(Debug, Clone)]
 pub struct Lexer {
-    s: String,
+    src: Rc<str>,
+    /// byte offset of the next character
     pos: usize,
+    file: Rc<PathBuf>,
+    line: usize,
+    column: usize,
 }
 
 impl Lexer {
-    pub fn new(path: PathBuf) -> Result<Self, String> {
-        if let Ok(f) = File::open(path.clone()) {
-            let mut s = "".to_string();
-            BufReader::new(f).read_to_string(&mut s).unwrap();
-
-            Ok(Lexer {
-                s: s.to_string(),
-                pos: 0,
-            })
+    pub fn new(path: PathBuf) -> Result<Self, CarpnError> {
+        let s = fs::read_to_string(&path).map_err(|e| CarpnError::io(&path, e))?;
+
+        Ok(Lexer::with_source(path, &s))
+    }
+
+    /// Lexes `src` as though it was read from `path`.
+    pub fn with_source(path: PathBuf, src: &str) -> Self {
+        Lexer {
+            src: Rc::from(src),
+            pos: 0,
+            file: Rc::new(path),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    pub fn file(&self) -> Rc<PathBuf> {
+        self.file.clone()
+    }
+
+    pub fn source(&self) -> Rc<str> {
+        self.src.clone()
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    /// The character `n` places after the next one.
+    fn peek_nth(&self, n: usize) -> Option<char> {
+        self.src[self.pos..].chars().nth(n)
+    }
+
+    /// Steps past the next character, keeping the line and column in step
+    /// with `pos`.
+    fn bump(&mut self) {
+        let Some(c) = self.peek() else {
+            return;
+        };
+
+        self.pos += c.len_utf8();
+
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
         } else {
-            Err(format!("Failed to open file: {}", path.display()))
+            self.column += 1;
+        }
+    }
+
+    /// Steps over a quoted literal, starting on the opening quote, and gives
+    /// whether the closing one was found before the end of the file. Char
+    /// literals must close on the line they open.
+    fn skip_quoted(&mut self, quote: char) -> bool {
+        self.bump();
+
+        loop {
+            match self.peek() {
+                None => return false,
+                Some('\n') if quote == '\'' => return false,
+                Some('\\') => {
+                    self.bump();
+                    self.bump();
+                }
+                Some(c) => {
+                    self.bump();
+                    if c == quote {
+                        return true;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Steps over a raw string with `hashes` `#`s around its quotes, giving
+    /// whether it was closed.
+    fn skip_raw_string(&mut self, hashes: usize) -> bool {
+        let close = format!("\"{}", "#".repeat(hashes));
+
+        for _ in 0..hashes + 2 {
+            self.bump();
+        }
+
+        while !self.src[self.pos..].starts_with(&close) {
+            if self.peek().is_none() {
+                return false;
+            }
+            self.bump();
+        }
+
+        for _ in 0..close.len() {
+            self.bump();
+        }
+        true
+    }
+
+    /// The number of `#`s after the `r` when a raw string starts here.
+    fn raw_string_start(&self) -> Option<usize> {
+        let rest = self.src[self.pos..].strip_prefix('r')?;
+        let body = rest.trim_start_matches('#');
+
+        body.starts_with('"').then_some(rest.len() - body.len())
+    }
+
+    fn skip_line(&mut self) {
+        while self.peek().is_some_and(|c| c != '\n') {
+            self.bump();
+        }
+    }
+
+    /// Steps over a `#( ... )#` comment, which may hold other block comments,
+    /// giving whether it was closed before the end of the file.
+    fn skip_block_comment(&mut self) -> bool {
+        let mut depth = 0;
+
+        loop {
+            let rest = &self.src[self.pos..];
+
+            if rest.starts_with("#(") {
+                depth += 1;
+            } else if rest.starts_with(")#") {
+                depth -= 1;
+            } else if rest.is_empty() {
+                return false;
+            } else {
+                self.bump();
+                continue;
+            }
+
+            self.bump();
+            self.bump();
+
+            if depth == 0 {
+                return true;
+            }
+        }
+    }
+
+    /// An empty span at the current position.
+    fn here(&self) -> Span {
+        Span {
+            file: self.file.clone(),
+            line: self.line,
+            column: self.column,
+            start: self.pos,
+            end: self.pos,
+        }
+    }
+
+    /// The span from `start` up to the current position.
+    fn span_from(&self, start: &Span) -> Span {
+        Span {
+            end: self.pos,
+            ..start.clone()
         }
     }
 }
@@ -121,7 +710,8 @@ struct BlockData {
     kind: BlockKind,
 }
 
-const SINGLES: [char; 7] = ['#', '*', '$', '.', ':', '=', '@']; // '[', ']', '{', '}'];
+const SINGLES: [char; 9] = ['#', '*', '$', '.', ':', '=', '@', '<', '>']; // '[', ']', '{', '}'];
+const DOUBLES: [&str; 5] = ["<<", ">>", "<=", ">=", "!="];
 const WHITESPACE: [char; 4] = ['#', ' ', '\n', '\t'];
 const BLOCKS: [BlockData; 2] = [
     BlockData {
@@ -137,100 +727,289 @@ const BLOCKS: [BlockData; 2] = [
 ];
 
 impl Iterator for Lexer {
-    type Item = Token;
+    type Item = Result<Spanned<Token>, Spanned<LexError>>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        let start = self.pos;
-
-        while WHITESPACE
-            .map(|x| Some(x))
-            .iter()
-            .any(|x| x == &self.s.chars().nth(self.pos))
+        while self
+            .peek()
+            .is_some_and(|c| c != '#' && WHITESPACE.contains(&c))
         {
-            if self.s.chars().nth(self.pos) == Some('#') {
-                self.pos += 1;
+            self.bump();
+        }
+
+        let start = self.here();
+        let c = self.peek()?;
 
-                while ![Some('\n'), None].contains(&self.s.chars().nth(self.pos)) {
-                    self.pos += 1;
+        // comments are kept as tokens for the formatter, the parser skips them
+        if self.src[self.pos..].starts_with("##") {
+            self.skip_line();
+
+            let text = &self.src[start.start + 2..self.pos];
+            let text = text.strip_prefix(' ').unwrap_or(text).trim_end();
+
+            return Some(Ok(Spanned {
+                node: Token::Doc(text.to_string()),
+                span: self.span_from(&start),
+            }));
+        }
+
+        if c == '#' {
+            if self.peek_nth(1) == Some('(') {
+                if !self.skip_block_comment() {
+                    return Some(Err(Spanned {
+                        node: LexError::UnterminatedComment,
+                        span: self.span_from(&start),
+                    }));
                 }
             } else {
-                self.pos += 1;
+                self.skip_line();
             }
-        }
 
-        if let Some(blk) = BLOCKS
-            .iter()
-            .find(|x| Some(x.start) == self.s.chars().nth(self.pos))
-        {
-            // self.pos += 1;
-            // let mut toks = Vec::new();
+            let text = self.src[start.start..self.pos].trim_end();
 
-            // while self.s.chars().nth(self.pos) != Some(blk.end) {
-            //     if self.s.chars().nth(self.pos) == None {
-            //         return None;
-            //     }
+            return Some(Ok(Spanned {
+                node: Token::Comment(text.to_string()),
+                span: self.span_from(&start),
+            }));
+        }
 
-            //     if let Some(tok) = self.next() {
-            //         toks.push(tok);
+        if let Some(blk) = BLOCKS.iter().find(|x| x.start == c) {
+            self.bump();
 
-            //         self.pos += 1;
-            //     }
-            // }
+            return Some(Ok(Spanned {
+                node: Token::BlockOpen(blk.kind.clone()),
+                span: self.span_from(&start),
+            }));
+        }
 
-            self.pos += 1;
+        if let Some(blk) = BLOCKS.iter().find(|x| x.end == c) {
+            self.bump();
 
-            return Some(Token::BlockOpen(blk.kind.clone()));
+            return Some(Ok(Spanned {
+                node: Token::BlockClose(blk.kind.clone()),
+                span: self.span_from(&start),
+            }));
         }
 
-        if let Some(blk) = BLOCKS
+        // a tick followed by an escape, or by one character and another tick
+        // that ends the token, is a char literal; a cast is always a lone
+        // `'`, so `' 'B'` casts to the type `'B'`
+        let char_literal = c == '\''
+            && (self.peek_nth(1) == Some('\\')
+                || (self.peek_nth(2) == Some('\'') && ends_token(self.peek_nth(3))));
+        let mut unterminated = None;
+
+        if c == '"' {
+            if !self.skip_quoted('"') {
+                unterminated = Some(LexError::UnterminatedString);
+            }
+        } else if char_literal {
+            if !self.skip_quoted('\'') {
+                unterminated = Some(LexError::UnterminatedChar);
+            }
+        } else if let Some(hashes) = self.raw_string_start() {
+            if !self.skip_raw_string(hashes) {
+                unterminated = Some(LexError::UnterminatedString);
+            }
+        } else if DOUBLES
             .iter()
-            .find(|x| Some(x.end) == self.s.chars().nth(self.pos))
+            .any(|op| self.src[self.pos..].starts_with(op))
         {
-            self.pos += 1;
+            self.bump();
+            self.bump();
+        } else if SINGLES.contains(&c) {
+            self.bump();
+        } else {
+            while let Some(c) = self.peek() {
+                // a dot between digits is a decimal point, not a prop access
+                let word = &self.src[start.start..self.pos];
+                let decimal = c == '.'
+                    && word
+                        .strip_prefix('-')
+                        .unwrap_or(word)
+                        .starts_with(|c: char| c.is_ascii_digit())
+                    && word.chars().skip(1).all(|c| c.is_ascii_digit() || c == '_')
+                    && self.peek_nth(1).is_some_and(|c| c.is_ascii_digit());
 
-            return Some(Token::BlockClose(blk.kind.clone()));
+                if !decimal && (SINGLES.contains(&c) || WHITESPACE.contains(&c)) {
+                    break;
+                }
+
+                self.bump();
+            }
         }
 
-        let mut tmp = "".to_string();
+        let tmp = &self.src[start.start..self.pos];
+        let span = self.span_from(&start);
 
-        if Some('"') == self.s.chars().nth(self.pos) {
-            self.pos += 1;
-            tmp.push('"');
-            while Some('"') != self.s.chars().nth(self.pos) {
-                tmp.push(self.s.chars().nth(self.pos).unwrap());
-                self.pos += 1;
+        Some(match unterminated.map_or_else(|| Token::new(tmp), Err) {
+            Ok(node) => Ok(Spanned { node, span }),
+            Err(node) => {
+                let span = match node.within() {
+                    Some(at) => Span {
+                        column: span.column + tmp[..at.start].chars().count(),
+                        start: span.start + at.start,
+                        end: span.start + at.end,
+                        ..span
+                    },
+                    None => span,
+                };
+
+                Err(Spanned { node, span })
             }
-            self.pos += 1;
-            tmp.push('"');
-        } else if SINGLES
-            .map(|x| Some(x))
-            .iter()
-            .any(|x| x == &self.s.chars().nth(self.pos))
-        {
-            tmp.push(self.s.chars().nth(self.pos).unwrap());
-            self.pos += 1;
-        } else {
-            while !(SINGLES
-                .map(|x| Some(x))
-                .iter()
-                .chain(WHITESPACE.map(|x| Some(x)).iter())
-                .chain([None].iter()))
-            .any(|x| x == &self.s.chars().nth(self.pos))
-            {
-                tmp.push(self.s.chars().nth(self.pos).unwrap());
-                self.pos += 1;
-            }
-        }
-
-        let result = if tmp == "" {
-            None
-        } else if let Ok(result) = Token::new(&tmp) {
-            Some(result)
-        } else {
-            None
-        };
-        println!("{:?}", result);
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every token in `src`, or the first error.
+    fn lex(src: &str) -> Result<Vec<Token>, Spanned<LexError>> {
+        Lexer::with_source(PathBuf::from("test.car"), src)
+            .map(|t| t.map(|t| t.node))
+            .collect()
+    }
+
+    fn token(src: &str) -> Token {
+        let tokens = lex(src).unwrap();
+        assert_eq!(tokens.len(), 1, "{:?}", tokens);
+        tokens.into_iter().next().unwrap()
+    }
+
+    fn error(src: &str) -> Spanned<LexError> {
+        lex(src).unwrap_err()
+    }
+
+    #[test]
+    fn integer_suffixes() {
+        assert_eq!(token("42"), Token::Int(42, None));
+        assert_eq!(token("200u8"), Token::Int(200, Some(NumSuffix::U8)));
+        assert_eq!(token("-5i64"), Token::Int(-5, Some(NumSuffix::I64)));
+        assert_eq!(token("3f32"), Token::Float(3.0, Some(NumSuffix::F32)));
+    }
+
+    #[test]
+    fn radixes_and_separators() {
+        assert_eq!(token("0xff"), Token::Int(255, None));
+        assert_eq!(token("0o31"), Token::Int(25, None));
+        assert_eq!(token("0b1010"), Token::Int(10, None));
+        assert_eq!(token("1_000_000"), Token::Int(1_000_000, None));
+        assert_eq!(token("-0x10"), Token::Int(-16, None));
+    }
+
+    #[test]
+    fn floats() {
+        assert_eq!(token("1.75"), Token::Float(1.75, None));
+        assert_eq!(token("-2.5e2"), Token::Float(-250.0, None));
+        assert_eq!(token("1e3f64"), Token::Float(1000.0, Some(NumSuffix::F64)));
+    }
+
+    #[test]
+    fn out_of_range() {
+        assert!(matches!(
+            error("256u8").node,
+            LexError::NumberOutOfRange { kind, .. } if kind == "u8"
+        ));
+        assert!(matches!(
+            error("-1u32").node,
+            LexError::NumberOutOfRange { kind, .. } if kind == "u32"
+        ));
+        assert!(matches!(
+            error("2147483648").node,
+            LexError::NumberOutOfRange { kind, .. } if kind == "i32"
+        ));
+        assert!(matches!(
+            error("1e40f32").node,
+            LexError::NumberOutOfRange { kind, .. } if kind == "f32"
+        ));
+
+        let e = error("1e400").node;
+        assert!(matches!(&e, LexError::NumberOutOfRange { kind, .. } if kind == "f64"));
+        assert_eq!(e.help(), None);
+    }
+
+    #[test]
+    fn invalid_suffix_points_at_the_suffix() {
+        let e = error("  10u7");
+        assert!(matches!(&e.node, LexError::InvalidSuffix { suffix, .. } if suffix == "u7"));
+        assert_eq!((e.span.start, e.span.end, e.span.column), (4, 6, 5));
+
+        assert!(matches!(error("1.5x").node, LexError::InvalidSuffix { .. }));
+        assert!(matches!(
+            error("5i128").node,
+            LexError::InvalidSuffix { .. }
+        ));
+    }
+
+    #[test]
+    fn invalid_digit_points_at_the_digit() {
+        let e = error("0xZZ");
+        assert!(matches!(
+            e.node,
+            LexError::InvalidDigit {
+                digit: 'Z',
+                radix: 16,
+                ..
+            }
+        ));
+        assert_eq!((e.span.start, e.span.end), (2, 3));
+
+        assert!(matches!(
+            error("0b102").node,
+            LexError::InvalidDigit { digit: '2', .. }
+        ));
+        assert!(matches!(error("0x").node, LexError::InvalidNumber(_)));
+    }
+
+    #[test]
+    fn char_literals_and_escapes() {
+        assert_eq!(token("'a'"), Token::Char('a'));
+        assert_eq!(token("' '"), Token::Char(' '));
+        assert_eq!(token("'\\n'"), Token::Char('\n'));
+        assert_eq!(token("'\\''"), Token::Char('\''));
+        assert_eq!(token("'\\x41'"), Token::Char('A'));
+        assert_eq!(token("'\\u{7e}'"), Token::Char('~'));
+
+        assert!(matches!(error("'\\q'").node, LexError::InvalidEscape(e) if e == "\\q"));
+        assert!(matches!(error("'é'").node, LexError::NonAsciiChar(_)));
+        assert!(matches!(
+            error("'\\"),
+            Spanned {
+                node: LexError::UnterminatedChar,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn string_escapes() {
+        assert_eq!(
+            token(r#""a\tb\"c\\""#),
+            Token::String("a\tb\"c\\".to_string())
+        );
+        assert_eq!(
+            token(r##"r#"no \n "here""#"##),
+            Token::String(r#"no \n "here""#.to_string())
+        );
+        assert_eq!(token("\"a\\\n    b\""), Token::String("ab".to_string()));
+    }
+
+    #[test]
+    fn tick_versus_char() {
+        let tick = Token::Op(Operation::Tick);
+
+        assert_eq!(lex("x i32 '").unwrap()[2], tick);
+        assert_eq!(lex("' 'B'").unwrap(), [tick.clone(), Token::Char('B')]);
+        assert_eq!(lex("'a'}").unwrap()[0], Token::Char('a'));
+        assert_eq!(lex("x f32 ' y").unwrap()[2], tick);
+    }
 
-        result
+    #[test]
+    fn words_starting_with_digits_are_identifiers() {
+        assert_eq!(token("2dup"), Token::Ident("2dup".to_string()));
+        assert_eq!(token("-rot"), Token::Ident("-rot".to_string()));
+        assert_eq!(token("-"), Token::Op(Operation::Minus));
     }
 }
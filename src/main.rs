@@ -1,39 +1,74 @@
+mod builtin;
+mod cexpr;
+mod check;
 mod cli;
+mod dump;
 mod error;
+mod format;
+mod json;
 mod lexer;
 mod parser;
+mod span;
 mod visit;
+// nothing outside the tests rewrites the tree yet, so `VisitMut` is unused
+#[cfg_attr(not(test), expect(dead_code))]
+mod walk;
 
 use cli::*;
 use error::*;
 use lexer::*;
 use parser::*;
+use span::*;
 use visit::*;
 
-use clap::{Command, Parser};
+use clap::Parser;
 
 use dirs::cache_dir;
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::fs::{create_dir_all, File};
-use std::io::Write;
+use std::fs::create_dir_all;
+use std::io::{self, Write};
 use std::process;
 use std::rc::Rc;
 
-fn main() -> Result<(), String> {
-    let args = Args::parse();
+fn main() {
+    if let Err(e) = run(Args::parse()) {
+        for d in e.diagnostics() {
+            eprint!("{}", d.render());
+        }
+        process::exit(1);
+    }
+}
+
+/// Writes a dump to stdout, ignoring a closed pipe so that it can be cut
+/// short by `head` and the like.
+fn emit(text: &str) {
+    io::stdout().write_all(text.as_bytes()).ok();
+}
 
+fn run(args: Args) -> Result<(), CarpnError> {
     match args {
         Args::C(c_args) => {
             for file in c_args.input {
                 let lex = Lexer::new(file.clone())?;
 
-                let tmp = CarpnFile::parse(&mut lex.peekable()).unwrap();
+                let mut tokens = Tokens::new(lex.clone())?;
+                if c_args.dump == Some(Stage::Tokens) {
+                    emit(&dump::tokens(lex));
+                    continue;
+                }
 
-                let mut cache = cache_dir().unwrap();
+                let tmp = CarpnFile::parse(&mut tokens)?;
+                if c_args.dump == Some(Stage::Ast) {
+                    emit(&dump::ast(&tmp));
+                    continue;
+                }
+
+                let mut cache =
+                    cache_dir().ok_or_else(|| CarpnError::Io("no cache directory".to_string()))?;
                 cache.push("carpn2");
 
-                create_dir_all(cache.clone()).unwrap();
+                create_dir_all(cache.clone()).map_err(|e| CarpnError::io(&cache, e))?;
 
                 let mut h_file = cache.clone();
                 let mut c_file = cache.clone();
@@ -46,8 +81,11 @@ fn main() -> Result<(), String> {
 
                 let mut ctx = VisitableCtx {
                     stack: Rc::new(RefCell::new(Vec::new())),
-                    vars: HashMap::new(),
+                    scopes: vec![Scope::new(ScopeKind::File)],
+                    ended: HashMap::new(),
                     procs: HashMap::new(),
+                    defs: HashMap::new(),
+                    expanding: Vec::new(),
                     inside: "".to_string(),
                     in_struct: None,
                     var_idx: 0,
@@ -56,35 +94,96 @@ fn main() -> Result<(), String> {
                     h_file: format!("{}", h_file.display()),
                     cache,
                     c_files: vec![format!("{}", c_file.display())],
+                    span: Span::start_of(Rc::new(file.clone())),
+                    trace: None,
+                    current: None,
                 };
 
-                ctx.vars
-                    .insert("void".to_string(), StackEntry::Type("void".to_string()));
-                ctx.vars.insert(
-                    "null".to_string(),
-                    StackEntry::Value("(void*)(0)".to_string(), HashMap::new()),
-                );
-                ctx.vars
-                    .insert("i32".to_string(), StackEntry::Type("int".to_string()));
-                ctx.vars
-                    .insert("i8".to_string(), StackEntry::Type("char".to_string()));
+                if c_args.dump == Some(Stage::Stack) {
+                    ctx.trace = Some(Rc::new(RefCell::new(Vec::new())));
+                }
+
+                ctx.declare_prelude();
+
+                let h_conts = tmp.header(&mut ctx)?;
+                if c_args.dump == Some(Stage::Symbols) {
+                    emit(&dump::symbols(&tmp, &ctx));
+                    continue;
+                }
+
+                check::check(&tmp, &ctx)?;
+
+                let c_conts = tmp.source(&mut ctx)?;
+                match c_args.dump {
+                    Some(Stage::Stack) => {
+                        for line in ctx.trace.iter().flat_map(|t| t.borrow().clone()) {
+                            emit(&format!("{}\n", line));
+                        }
+                        continue;
+                    }
+                    Some(Stage::C) => {
+                        emit(&format!("// {}\n{}\n", h_file.display(), h_conts));
+                        emit(&format!("// {}\n{}\n", c_file.display(), c_conts));
+                        continue;
+                    }
+                    _ => {}
+                }
+
+                write_file(&c_file, &c_conts)?;
+                write_file(&h_file, &h_conts)?;
+
+                let output = process::Command::new(c_args.cc.as_str())
+                    .args(ctx.c_files)
+                    .output()
+                    .map_err(|e| CarpnError::Io(format!("{}: {}", c_args.cc, e)))?;
 
-                println!("{:?}", tmp);
-                println!("==========");
+                if !output.status.success() {
+                    io::stderr().write_all(&output.stderr).ok();
+
+                    return Err(CarpnError::CCompile(c_args.cc));
+                }
+            }
 
-                let h_conts = tmp.header(&mut ctx);
-                let c_conts = tmp.source(&mut ctx);
+            Ok(())
+        }
+        Args::Fmt(f_args) => {
+            let mut unformatted = Vec::new();
 
-                let mut file = File::create(c_file.clone()).unwrap();
-                file.write_all(c_conts.as_str().as_bytes()).unwrap();
+            for file in f_args.input {
+                let lex = Lexer::new(file.clone())?;
+                let parsed = CarpnFile::parse(&mut Tokens::new(lex.clone())?)?;
+                let formatted = format::format(lex.clone(), &parsed);
+
+                if formatted == *lex.source() {
+                    continue;
+                }
+
+                if f_args.check {
+                    unformatted.push(file);
+                } else {
+                    write_file(&file, &formatted)?;
+                }
+            }
 
-                let mut file = File::create(h_file.clone()).unwrap();
-                file.write_all(h_conts.as_str().as_bytes()).unwrap();
+            if !unformatted.is_empty() {
+                return Err(CarpnError::Unformatted(unformatted));
+            }
 
-                process::Command::new(c_args.cc.as_str())
-                    .args(ctx.c_files)
-                    .output()
-                    .unwrap();
+            Ok(())
+        }
+        Args::Parse(p_args) => {
+            for file in p_args.input {
+                let lex = Lexer::new(file.clone())?;
+                let parsed =
+                    Tokens::new(lex.clone()).and_then(|mut tokens| CarpnFile::parse(&mut tokens));
+
+                match p_args.format {
+                    Format::Text => emit(&dump::ast(&parsed?)),
+                    Format::Json => {
+                        emit(&format!("{}\n", json::document(&file, lex, &parsed)));
+                        parsed?;
+                    }
+                }
             }
 
             Ok(())
@@ -1,50 +1,366 @@
-use crate::{BlockKind, Lexer, Operation, ParseError, Token};
-use std::iter::Peekable;
+use crate::span::{Span, Spanned};
+use crate::{BlockKind, CarpnError, Lexer, NumSuffix, Operation, ParseError, Token};
+use serde::{Serialize, Serializer};
+use std::collections::HashMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// The token stream as the parser sees it.
+///
+/// The whole file is lexed up front so the parser can look any number of
+/// tokens ahead, and nodes can record the full span of source they were
+/// parsed from.
+pub struct Tokens {
+    tokens: Vec<Spanned<Token>>,
+    /// doc comments, by the index of the token they come before
+    docs: HashMap<usize, String>,
+    pos: usize,
+    file: Rc<PathBuf>,
+}
+
+impl Tokens {
+    /// Lexes the whole file up front. Every invalid token is reported, not
+    /// just the first.
+    pub fn new(lex: Lexer) -> Result<Self, CarpnError> {
+        let file = lex.file();
+        let mut tokens = Vec::new();
+        let mut docs: HashMap<usize, String> = HashMap::new();
+        let mut errors = Vec::new();
+
+        for token in lex {
+            match token {
+                Ok(Spanned {
+                    node: Token::Doc(text),
+                    ..
+                }) => {
+                    let doc = docs.entry(tokens.len()).or_default();
+                    if !doc.is_empty() {
+                        doc.push('\n');
+                    }
+                    *doc += &text;
+                }
+                Ok(Spanned {
+                    node: Token::Comment(_),
+                    ..
+                }) => {}
+                Ok(token) => tokens.push(token),
+                Err(e) => errors.push(e),
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(CarpnError::Lex(errors));
+        }
+
+        Ok(Tokens {
+            file,
+            tokens,
+            docs,
+            pos: 0,
+        })
+    }
+
+    pub fn peek(&self) -> Option<&Token> {
+        self.peek_nth(0)
+    }
+
+    /// The token `n` places after the next one.
+    pub fn peek_nth(&self, n: usize) -> Option<&Token> {
+        self.tokens.get(self.pos + n).map(|t| &t.node)
+    }
+
+    pub fn next(&mut self) -> Option<Token> {
+        let t = self.tokens.get(self.pos)?;
+        self.pos += 1;
+
+        Some(t.node.clone())
+    }
+
+    /// The doc comment written just before the next token.
+    pub fn doc(&self) -> Option<String> {
+        self.docs.get(&self.pos).cloned()
+    }
+
+    fn last(&self) -> Option<&Span> {
+        self.pos.checked_sub(1).map(|i| &self.tokens[i].span)
+    }
+
+    /// The span of the next token, or an empty span after the last one.
+    pub fn here(&self) -> Span {
+        if let Some(t) = self.tokens.get(self.pos) {
+            return t.span.clone();
+        }
+
+        match self.last() {
+            Some(last) => Span {
+                start: last.end,
+                ..last.clone()
+            },
+            None => Span::start_of(self.file.clone()),
+        }
+    }
+
+    /// The span from `start` to the end of the last token consumed.
+    pub fn span_from(&self, start: &Span) -> Span {
+        match self.last() {
+            Some(last) => start.to(last),
+            None => start.clone(),
+        }
+    }
+
+    /// Whether the token `n` places after the next one is written right up
+    /// against the token before it, with no space between.
+    pub fn touches(&self, n: usize) -> bool {
+        let i = self.pos + n;
+
+        match (
+            i.checked_sub(1).and_then(|i| self.tokens.get(i)),
+            self.tokens.get(i),
+        ) {
+            (Some(a), Some(b)) => a.span.end == b.span.start,
+            _ => false,
+        }
+    }
+
+    /// Skips past the rest of the item that started at token `start`.
+    ///
+    /// Outside of braces, this stops at the next token that starts an item.
+    /// An error inside braces skips to the `}` that closes them, so the
+    /// rest of a struct isn't read as top level items. A `}` with no `{`
+    /// before it in the item is left alone, it can't close anything the
+    /// item opened. Braces that never close fall back to skipping to the
+    /// next token that starts an item.
+    pub fn recover(&mut self, start: usize) {
+        let mut depth = 0;
+        for (i, t) in self.tokens.iter().enumerate().skip(start) {
+            if i > start && i >= self.pos && depth == 0 && t.starts_item() {
+                self.pos = i;
+                return;
+            }
+
+            match t.node {
+                Token::BlockOpen(BlockKind::Curly) => depth += 1,
+                Token::BlockClose(BlockKind::Curly) if depth > 0 => {
+                    depth -= 1;
+
+                    if depth == 0 && i >= self.pos {
+                        self.pos = i + 1;
+                        return;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        while self.peek().is_some_and(|t| !t.starts_item()) {
+            _ = self.next();
+        }
+    }
+
+    /// An error pointing at the next token.
+    pub fn error(&self, error: ParseError) -> Spanned<ParseError> {
+        Spanned {
+            node: error,
+            span: self.here(),
+        }
+    }
+
+    /// Runs `parse`, attaching the span of the tokens it consumed.
+    pub fn spanned<T>(
+        &mut self,
+        parse: impl FnOnce(&mut Self) -> ParseResult<T>,
+    ) -> ParseResult<Spanned<T>> {
+        let start = self.here();
+        let node = parse(self)?;
+
+        Ok(Spanned {
+            node,
+            span: self.span_from(&start),
+        })
+    }
+}
+
+pub type ParseResult<T> = Result<T, Spanned<ParseError>>;
 
 pub trait Parsable {
-    fn parse(lex: &mut Peekable<Lexer>) -> Result<Self, ParseError>
+    fn parse(lex: &mut Tokens) -> ParseResult<Self>
     where
         Self: Sized;
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
 pub struct IfExpression {
-    pub body: Vec<Expression>,
+    pub body: Vec<Spanned<Expression>>,
+    pub else_body: Option<Vec<Spanned<Expression>>>,
 }
 
 impl Parsable for IfExpression {
-    fn parse(lex: &mut Peekable<Lexer>) -> Result<Self, ParseError> {
+    fn parse(lex: &mut Tokens) -> ParseResult<Self> {
         if lex.peek() != Some(&Token::If) {
-            return Err(ParseError::MissingBody);
+            return Err(lex.error(ParseError::MissingBody));
         }
 
         _ = lex.next();
 
         if lex.peek() != Some(&Token::BlockOpen(BlockKind::Curly)) {
-            return Err(ParseError::MissingBody);
+            return Err(lex.error(ParseError::MissingBody));
         }
 
         _ = lex.next();
 
-        let mut body = Vec::new();
+        let body = parse_exprs(lex)?;
+
+        close_block(lex)?;
 
-        while let Ok(expr) = Expression::parse(lex) {
-            body.push(expr);
+        let mut else_body = None;
+
+        if lex.peek() == Some(&Token::Else) {
+            _ = lex.next();
+
+            else_body = Some(parse_block(lex)?);
         }
 
-        println!("{:?}", body);
+        Ok(IfExpression { body, else_body })
+    }
+}
 
-        if lex.peek() != Some(&Token::BlockClose(BlockKind::Curly)) {
-            return Err(ParseError::MissingBody);
+/// Parses expressions up to the first token that cannot start one.
+fn parse_exprs(lex: &mut Tokens) -> ParseResult<Vec<Spanned<Expression>>> {
+    let mut exprs = Vec::new();
+
+    loop {
+        match lex.spanned(Expression::parse) {
+            Ok(expr) => exprs.push(expr),
+            Err(e) if matches!(e.node, ParseError::InvalidExpression) => return Ok(exprs),
+            Err(e) => return Err(e),
         }
+    }
+}
+
+/// Consumes the `}` that closes a block.
+fn close_block(lex: &mut Tokens) -> ParseResult<()> {
+    match lex.peek() {
+        Some(Token::BlockClose(BlockKind::Curly)) => {
+            _ = lex.next();
+            Ok(())
+        }
+        Some(t) if !t.starts_item() => {
+            let t = t.to_string();
+            Err(lex.error(ParseError::UnexpectedToken(t)))
+        }
+        _ => Err(lex.error(ParseError::MissingCloseCurly)),
+    }
+}
+
+fn parse_block(lex: &mut Tokens) -> ParseResult<Vec<Spanned<Expression>>> {
+    if lex.peek() != Some(&Token::BlockOpen(BlockKind::Curly)) {
+        return Err(lex.error(ParseError::MissingBody));
+    }
+
+    _ = lex.next();
+
+    let body = parse_exprs(lex)?;
+
+    close_block(lex)?;
+
+    Ok(body)
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct WhileExpression {
+    pub cond: Vec<Spanned<Expression>>,
+    pub body: Vec<Spanned<Expression>>,
+}
+
+impl Parsable for WhileExpression {
+    fn parse(lex: &mut Tokens) -> ParseResult<Self> {
+        if lex.next() != Some(Token::While) {
+            return Err(lex.error(ParseError::MissingBody));
+        }
+
+        let cond = parse_block(lex)?;
+        let body = parse_block(lex)?;
+
+        Ok(WhileExpression { cond, body })
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct LoopExpression {
+    pub body: Vec<Spanned<Expression>>,
+}
+
+impl Parsable for LoopExpression {
+    fn parse(lex: &mut Tokens) -> ParseResult<Self> {
+        if lex.next() != Some(Token::Loop) {
+            return Err(lex.error(ParseError::MissingBody));
+        }
+
+        let body = parse_block(lex)?;
+
+        Ok(LoopExpression { body })
+    }
+}
+
+/// A named parameter in a prototype, written `name:type`.
+#[derive(Debug, PartialEq, Clone, Serialize)]
+pub struct Param {
+    pub name: String,
+    pub kind: Vec<Spanned<Expression>>,
+}
 
+impl Parsable for Param {
+    fn parse(lex: &mut Tokens) -> ParseResult<Self> {
+        let Some(Token::Ident(name)) = lex.next() else {
+            return Err(lex.error(ParseError::Unreachable));
+        };
         _ = lex.next();
 
-        Ok(IfExpression { body })
+        // the type runs on for as long as its words touch
+        let mut kind = Vec::new();
+        while lex.touches(0) {
+            match lex.spanned(Expression::parse) {
+                Ok(expr) => kind.push(expr),
+                Err(e) if matches!(e.node, ParseError::InvalidExpression) => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if kind.is_empty() {
+            return Err(lex.error(ParseError::MissingParamType(name)));
+        }
+
+        Ok(Param { name, kind })
     }
 }
 
-#[derive(Debug, PartialEq)]
+/// Parses the arguments of a prototype. A name with a `:` right after it
+/// starts a named parameter, anything else is a type as in a body.
+fn parse_args(lex: &mut Tokens) -> ParseResult<Vec<Spanned<Expression>>> {
+    let mut args = Vec::new();
+
+    loop {
+        let named = matches!(
+            (lex.peek(), lex.peek_nth(1)),
+            (Some(Token::Ident(_)), Some(Token::Op(Operation::Colon)))
+        ) && lex.touches(1);
+
+        let arg = if named {
+            lex.spanned(|lex| Param::parse(lex).map(Expression::Param))
+        } else {
+            lex.spanned(Expression::parse)
+        };
+
+        match arg {
+            Ok(arg) => args.push(arg),
+            Err(e) if matches!(e.node, ParseError::InvalidExpression) => return Ok(args),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
 pub enum ExprOp {
     Name(String),
     Equal,
@@ -57,25 +373,120 @@ pub enum ExprOp {
     LessThan,
     GreaterThan,
     Deref,
+    Divide,
+    Modulo,
+    BitAnd,
+    BitOr,
+    BitXor,
+    ShiftLeft,
+    ShiftRight,
+    LessEqual,
+    GreaterEqual,
+    NotEqual,
+    And,
+    Or,
+    Not,
+    Negate,
+}
+
+impl Serialize for ExprOp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl fmt::Display for ExprOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            ExprOp::Name(name) => return write!(f, "=> {}", name),
+            ExprOp::Equal => "==",
+            ExprOp::Assign => "=",
+            ExprOp::Star => "*",
+            ExprOp::Dollar => "$",
+            ExprOp::Minus => "-",
+            ExprOp::Plus => "+",
+            ExprOp::Tick => "'",
+            ExprOp::LessThan => "<",
+            ExprOp::GreaterThan => ">",
+            ExprOp::Deref => "@",
+            ExprOp::Divide => "/",
+            ExprOp::Modulo => "%",
+            ExprOp::BitAnd => "&",
+            ExprOp::BitOr => "|",
+            ExprOp::BitXor => "^",
+            ExprOp::ShiftLeft => "<<",
+            ExprOp::ShiftRight => ">>",
+            ExprOp::LessEqual => "<=",
+            ExprOp::GreaterEqual => ">=",
+            ExprOp::NotEqual => "!=",
+            ExprOp::And => "and",
+            ExprOp::Or => "or",
+            ExprOp::Not => "not",
+            ExprOp::Negate => "neg",
+        };
+
+        write!(f, "{}", s)
+    }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone, Serialize)]
+#[serde(tag = "kind", content = "value", rename_all = "snake_case")]
 pub enum Expression {
-    Int(i64),
-    Float(f64),
+    Int(i128, Option<NumSuffix>),
+    Float(f64, Option<NumSuffix>),
     Ident(String),
     Op(ExprOp),
     If(IfExpression),
+    While(WhileExpression),
+    Loop(LoopExpression),
+    Param(Param),
     String(String),
+    Char(char),
     Prop(String),
     Return,
 }
 
+impl Expression {
+    /// Whether control never gets past the expression: a `ret`, an `if`
+    /// whose arms both diverge, or a `loop`, which only `ret` leaves.
+    pub fn diverges(&self) -> bool {
+        match self {
+            Expression::Return | Expression::Loop(_) => true,
+            Expression::If(i) => diverges(&i.body) && i.else_body.as_deref().is_some_and(diverges),
+            _ => false,
+        }
+    }
+}
+
+/// Whether control never gets past the end of `body`.
+pub fn diverges(body: &[Spanned<Expression>]) -> bool {
+    body.iter().any(|e| e.diverges())
+}
+
+/// How the expression is written in source. Blocks only show their keyword.
+impl fmt::Display for Expression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Expression::Int(i, suffix) => Token::Int(*i, *suffix).fmt(f),
+            Expression::Float(x, suffix) => Token::Float(*x, *suffix).fmt(f),
+            Expression::Ident(s) => write!(f, "{}", s),
+            Expression::Op(op) => write!(f, "{}", op),
+            Expression::If(_) => write!(f, "if"),
+            Expression::While(_) => write!(f, "while"),
+            Expression::Loop(_) => write!(f, "loop"),
+            Expression::Param(p) => write!(f, "{}:", p.name),
+            Expression::String(s) => Token::String(s.clone()).fmt(f),
+            Expression::Char(c) => Token::Char(*c).fmt(f),
+            Expression::Prop(p) => write!(f, ".{}", p),
+            Expression::Return => write!(f, "ret"),
+        }
+    }
+}
+
 impl Parsable for Expression {
-    fn parse(lex: &mut Peekable<Lexer>) -> Result<Self, ParseError> {
-        let mut tmp = lex.clone();
-        let a = tmp.next();
-        let b = tmp.next();
+    fn parse(lex: &mut Tokens) -> ParseResult<Self> {
+        let a = lex.peek().cloned();
+        let b = lex.peek_nth(1).cloned();
         match (a, b) {
             (Some(Token::Op(Operation::Dot)), Some(Token::Ident(name))) => {
                 _ = lex.next();
@@ -83,8 +494,10 @@ impl Parsable for Expression {
                 Ok(Expression::Prop(name))
             }
             (Some(Token::Op(Operation::Equal)), Some(Token::Op(Operation::Gt))) => {
-                let Some(Token::Ident(name)) = tmp.next() else {
-                    todo!();
+                let Some(Token::Ident(name)) = lex.peek_nth(2).cloned() else {
+                    _ = lex.next();
+                    _ = lex.next();
+                    return Err(lex.error(ParseError::MissingBindingName));
                 };
 
                 _ = lex.next();
@@ -133,17 +546,73 @@ impl Parsable for Expression {
                 _ = lex.next();
                 Ok(Expression::Op(ExprOp::LessThan))
             }
+            (Some(Token::Op(Operation::Slash)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::Divide))
+            }
+            (Some(Token::Op(Operation::Percent)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::Modulo))
+            }
+            (Some(Token::Op(Operation::Amp)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::BitAnd))
+            }
+            (Some(Token::Op(Operation::Pipe)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::BitOr))
+            }
+            (Some(Token::Op(Operation::Caret)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::BitXor))
+            }
+            (Some(Token::Op(Operation::Shl)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::ShiftLeft))
+            }
+            (Some(Token::Op(Operation::Shr)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::ShiftRight))
+            }
+            (Some(Token::Op(Operation::Le)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::LessEqual))
+            }
+            (Some(Token::Op(Operation::Ge)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::GreaterEqual))
+            }
+            (Some(Token::Op(Operation::Ne)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::NotEqual))
+            }
+            (Some(Token::Op(Operation::And)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::And))
+            }
+            (Some(Token::Op(Operation::Or)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::Or))
+            }
+            (Some(Token::Op(Operation::Not)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::Not))
+            }
+            (Some(Token::Op(Operation::Neg)), _) => {
+                _ = lex.next();
+                Ok(Expression::Op(ExprOp::Negate))
+            }
             (Some(Token::Ret), _) => {
                 _ = lex.next();
                 Ok(Expression::Return)
             }
-            (Some(Token::Int(i)), _) => {
+            (Some(Token::Int(i, suffix)), _) => {
                 _ = lex.next();
-                Ok(Expression::Int(i))
+                Ok(Expression::Int(i, suffix))
             }
-            (Some(Token::Float(f)), _) => {
+            (Some(Token::Float(f, suffix)), _) => {
                 _ = lex.next();
-                Ok(Expression::Float(f))
+                Ok(Expression::Float(f, suffix))
             }
             (Some(Token::Ident(s)), _) => {
                 _ = lex.next();
@@ -153,66 +622,100 @@ impl Parsable for Expression {
                 _ = lex.next();
                 Ok(Expression::String(s))
             }
+            (Some(Token::Char(c)), _) => {
+                _ = lex.next();
+                Ok(Expression::Char(c))
+            }
             (Some(Token::If), _) => {
                 let expr = IfExpression::parse(lex)?;
                 Ok(Expression::If(expr))
             }
-            _ => Err(ParseError::InvalidExpression),
+            (Some(Token::While), _) => {
+                let expr = WhileExpression::parse(lex)?;
+                Ok(Expression::While(expr))
+            }
+            (Some(Token::Loop), _) => {
+                let expr = LoopExpression::parse(lex)?;
+                Ok(Expression::Loop(expr))
+            }
+            _ => Err(lex.error(ParseError::InvalidExpression)),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Prototype {
     pub name: String,
-    pub args: Vec<Expression>,
-    pub result: Option<Vec<Expression>>,
+    pub span: Span,
+    pub args: Vec<Spanned<Expression>>,
+    pub result: Option<Vec<Spanned<Expression>>>,
 }
 
 impl Parsable for Prototype {
-    fn parse(lex: &mut Peekable<Lexer>) -> Result<Self, ParseError> {
-        let Some(Token::Ident(name)) = lex.next() else {
-            return Err(ParseError::PrototypeMissingName);
+    fn parse(lex: &mut Tokens) -> ParseResult<Self> {
+        let start = lex.here();
+        let Some(Token::Ident(name)) = lex.peek().cloned() else {
+            return Err(lex.error(ParseError::PrototypeMissingName));
         };
+        _ = lex.next();
 
-        let mut args = Vec::new();
+        let args = parse_args(lex)?;
         let mut result = None;
 
-        while let Ok(expr) = Expression::parse(lex) {
-            args.push(expr);
+        let mut names = Vec::new();
+        for arg in &args {
+            let Expression::Param(p) = &arg.node else {
+                continue;
+            };
+
+            if names.contains(&&p.name) {
+                return Err(Spanned {
+                    node: ParseError::DuplicateParam(p.name.clone()),
+                    span: arg.span.clone(),
+                });
+            }
+            names.push(&p.name);
         }
 
         if lex.peek() == Some(&Token::Op(Operation::Colon)) {
-            let mut tmp_result = Vec::new();
             _ = lex.next();
 
-            while let Ok(expr) = Expression::parse(lex) {
-                tmp_result.push(expr);
-            }
-
-            result = Some(tmp_result);
+            result = Some(parse_exprs(lex)?);
         }
 
-        Ok(Prototype { name, args, result })
+        Ok(Prototype {
+            name,
+            span: lex.span_from(&start),
+            args,
+            result,
+        })
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Serialize)]
+#[serde(tag = "kind", content = "body", rename_all = "snake_case")]
 pub enum ProcBody {
     Extern,
-    Impl(Vec<Expression>),
+    Impl(Vec<Spanned<Expression>>),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Proc {
+    pub doc: Option<String>,
     pub def: Prototype,
     pub body: ProcBody,
+    pub span: Span,
+    /// the `}` that closes the body, or the end of an extern
+    #[serde(skip)]
+    pub end: Span,
 }
 
 impl Parsable for Proc {
-    fn parse(lex: &mut Peekable<Lexer>) -> Result<Self, ParseError> {
+    fn parse(lex: &mut Tokens) -> ParseResult<Self> {
+        let start = lex.here();
+        let doc = lex.doc();
         let Some(first) = lex.next() else {
-            return Err(ParseError::ParserEOF);
+            return Err(lex.error(ParseError::ParserEOF));
         };
 
         let def = Prototype::parse(lex)?;
@@ -222,68 +725,102 @@ impl Parsable for Proc {
         match first {
             Token::Proc => {
                 if lex.peek() != Some(&Token::BlockOpen(BlockKind::Curly)) {
-                    return Err(ParseError::MissingBody);
+                    return Err(lex.error(ParseError::MissingBody));
                 }
 
                 _ = lex.next();
 
-                let mut body_conts = Vec::new();
-
-                while let Ok(expr) = Expression::parse(lex) {
-                    body_conts.push(expr);
-                }
-
-                body = ProcBody::Impl(body_conts);
+                body = ProcBody::Impl(parse_exprs(lex)?);
 
-                if lex.peek() != Some(&Token::BlockClose(BlockKind::Curly)) {
-                    print!("{:?}", body);
-
-                    return Err(ParseError::MissingCloseCurly);
-                }
-
-                _ = lex.next();
+                close_block(lex)?;
             }
             Token::Extern => {}
             _ => {
-                return Err(ParseError::Unreachable);
+                return Err(lex.error(ParseError::Unreachable));
             }
         }
 
-        Ok(Proc { def, body })
+        Ok(Proc {
+            doc,
+            def,
+            body,
+            span: lex.span_from(&start),
+            end: lex.last().cloned().unwrap_or(start),
+        })
     }
 }
 
-#[derive(Debug)]
+/// A named block that is inlined wherever its name is used.
+#[derive(Debug, Clone, Serialize)]
+pub struct Def {
+    pub doc: Option<String>,
+    pub name: String,
+    pub body: Vec<Spanned<Expression>>,
+    pub span: Span,
+}
+
+impl Parsable for Def {
+    fn parse(lex: &mut Tokens) -> ParseResult<Self> {
+        let start = lex.here();
+        let doc = lex.doc();
+        if lex.next() != Some(Token::Def) {
+            return Err(lex.error(ParseError::MissingBody));
+        }
+
+        let Some(Token::Ident(name)) = lex.peek().cloned() else {
+            return Err(lex.error(ParseError::MissingDefName));
+        };
+        _ = lex.next();
+
+        let body = parse_block(lex)?;
+
+        Ok(Def {
+            doc,
+            name,
+            body,
+            span: lex.span_from(&start),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
 pub struct Struct {
+    pub doc: Option<String>,
     pub name: String,
+    pub defs: Vec<Def>,
     pub procs: Vec<Proc>,
     pub structs: Vec<Struct>,
-    pub body: Vec<Expression>,
+    pub body: Vec<Spanned<Expression>>,
+    pub span: Span,
 }
 
 impl Parsable for Struct {
-    fn parse(lex: &mut Peekable<Lexer>) -> Result<Self, ParseError> {
+    fn parse(lex: &mut Tokens) -> ParseResult<Self> {
+        let start = lex.here();
+        let doc = lex.doc();
         if lex.next() != Some(Token::Struct) {
-            return Err(ParseError::MissingBody);
+            return Err(lex.error(ParseError::MissingBody));
         }
 
-        let Some(Token::Ident(name)) = lex.next() else {
-            return Err(ParseError::MissingStructName);
+        let Some(Token::Ident(name)) = lex.peek().cloned() else {
+            return Err(lex.error(ParseError::MissingStructName));
         };
+        _ = lex.next();
 
+        let mut defs = Vec::new();
         let mut procs = Vec::new();
         let mut structs = Vec::new();
         let mut body = Vec::new();
 
         if lex.peek() != Some(&Token::BlockOpen(BlockKind::Curly)) {
-            return Err(ParseError::MissingBody);
+            return Err(lex.error(ParseError::MissingBody));
         }
 
         lex.next();
 
         loop {
             let Some(first) = lex.peek() else {
-                return Err(ParseError::MissingCloseCurly);
+                return Err(lex.error(ParseError::MissingCloseCurly));
             };
 
             match first {
@@ -301,8 +838,13 @@ impl Parsable for Struct {
 
                     structs.push(s);
                 }
+                Token::Def => {
+                    let d = Def::parse(lex)?;
+
+                    defs.push(d);
+                }
                 _ => {
-                    let t = Expression::parse(lex)?;
+                    let t = lex.spanned(Expression::parse)?;
 
                     body.push(t);
                 }
@@ -310,100 +852,176 @@ impl Parsable for Struct {
         }
 
         if lex.peek() != Some(&Token::BlockClose(BlockKind::Curly)) {
-            return Err(ParseError::MissingBody);
+            return Err(lex.error(ParseError::MissingBody));
         }
 
         lex.next();
 
         Ok(Struct {
+            doc,
             name,
+            defs,
             structs,
             procs,
             body,
+            span: lex.span_from(&start),
         })
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct Include {
     pub file: String,
     pub is_c: bool,
+    pub span: Span,
 }
 
 impl Parsable for Include {
-    fn parse(lex: &mut Peekable<Lexer>) -> Result<Self, ParseError> {
-        let mut tmp = lex.clone();
-        let first = tmp.next();
-        let Some(Token::String(file)) = tmp.next() else {
-            todo!("{:?}", lex.peek())
+    fn parse(lex: &mut Tokens) -> ParseResult<Self> {
+        let start = lex.here();
+        let first = lex.peek().cloned();
+        let Some(Token::String(file)) = lex.peek_nth(1).cloned() else {
+            _ = lex.next();
+            return Err(lex.error(ParseError::MissingIncludePath));
         };
         match &first {
             Some(Token::CInclude) => {
                 _ = lex.next();
                 _ = lex.next();
 
-                return Ok(Include {
+                Ok(Include {
                     file: file.to_string(),
                     is_c: true,
-                });
+                    span: lex.span_from(&start),
+                })
             }
             Some(Token::Include) => {
                 _ = lex.next();
                 _ = lex.next();
 
-                return Ok(Include {
+                Ok(Include {
                     file: file.to_string(),
                     is_c: false,
-                });
+                    span: lex.span_from(&start),
+                })
             }
-            _ => todo!(),
+            _ => Err(lex.error(ParseError::Unreachable)),
         }
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Serialize)]
 pub struct CarpnFile {
     pub includes: Vec<Include>,
+    pub defs: Vec<Def>,
     pub procs: Vec<Proc>,
     pub structs: Vec<Struct>,
 }
 
-impl Parsable for CarpnFile {
-    fn parse(lex: &mut Peekable<Lexer>) -> Result<Self, ParseError> {
+impl CarpnFile {
+    /// Parses a whole file, picking up again at the next top level item
+    /// after a syntax error so that every error in the file is reported.
+    pub fn parse(lex: &mut Tokens) -> Result<Self, CarpnError> {
         let mut procs = Vec::new();
         let mut structs = Vec::new();
         let mut includes = Vec::new();
+        let mut defs = Vec::new();
+        let mut errors = Vec::new();
 
-        loop {
-            let Some(first) = lex.peek() else {
-                break;
-            };
-
-            match first {
-                Token::CInclude | Token::Include => {
-                    let i = Include::parse(lex)?;
-
-                    includes.push(i);
-                }
-                Token::Proc | Token::Extern => {
-                    let p = Proc::parse(lex)?;
-
-                    procs.push(p);
-                }
-                Token::Struct => {
-                    let s = Struct::parse(lex)?;
+        while let Some(first) = lex.peek() {
+            let start = lex.pos;
+            let parsed = match first {
+                Token::CInclude | Token::Include => Include::parse(lex).map(|i| includes.push(i)),
+                Token::Proc | Token::Extern => Proc::parse(lex).map(|p| procs.push(p)),
+                Token::Struct => Struct::parse(lex).map(|s| structs.push(s)),
+                Token::Def => Def::parse(lex).map(|d| defs.push(d)),
 
-                    structs.push(s);
+                _ => {
+                    let e = lex.error(ParseError::ExpectedItem);
+                    _ = lex.next();
+                    Err(e)
                 }
+            };
 
-                _ => return Err(ParseError::ParserEOF),
+            if let Err(e) = parsed {
+                errors.push(e);
+                lex.recover(start);
             }
         }
 
+        if !errors.is_empty() {
+            return Err(CarpnError::Parse(errors));
+        }
+
         Ok(CarpnFile {
             includes,
+            defs,
             procs,
             structs,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The messages of every parse error in `src`.
+    fn errors(src: &str) -> Vec<String> {
+        let lex = Lexer::with_source(PathBuf::from("test.car"), src);
+        let mut tokens = Tokens::new(lex).unwrap();
+
+        match CarpnFile::parse(&mut tokens) {
+            Err(CarpnError::Parse(errors)) => errors.iter().map(|e| e.node.to_string()).collect(),
+            Err(e) => panic!("{}", e),
+            Ok(_) => Vec::new(),
+        }
+    }
+
+    #[test]
+    fn error_inside_struct_skips_the_struct() {
+        let errors = errors("struct A { proc f : i32 { 1 ] } proc g : i32 { 1 } } proc h { }");
+        assert_eq!(errors, ["unexpected `]`"]);
+    }
+
+    #[test]
+    fn every_item_is_reported() {
+        let errors = errors("proc f { ] } proc g { 1 } proc h { ] }");
+        assert_eq!(errors.len(), 2);
+    }
+
+    #[test]
+    fn params_are_named_once() {
+        assert_eq!(
+            errors("proc f a:i32 b:i32 a:u8 : i32 { a }"),
+            ["parameter `a` is named twice"]
+        );
+        assert!(errors("proc g a:i32 b:i32 i32 : i32 { a }").is_empty());
+    }
+
+    #[test]
+    fn stray_close_resumes_at_the_next_item() {
+        let errors = errors("}\nproc g : i32 { ] }\nproc h : i32 { ] }");
+        assert_eq!(
+            errors,
+            [
+                "expected a top level item",
+                "unexpected `]`",
+                "unexpected `]`"
+            ]
+        );
+    }
+
+    #[test]
+    fn item_without_braces_resumes_at_the_next_item() {
+        let errors = errors("extern 5\nproc g : i32 { ] }\nproc h : i32 { ] }");
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[1..], ["unexpected `]`", "unexpected `]`"]);
+    }
+
+    #[test]
+    fn unclosed_braces_resume_at_the_next_item() {
+        let errors = errors("proc f { ]\nproc g { ] }");
+        assert_eq!(errors.len(), 2);
+    }
+}
@@ -0,0 +1,73 @@
+use serde::{ser::SerializeStruct, Serialize, Serializer};
+use std::fmt;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::rc::Rc;
+
+/// A range of source text, with the line and column it starts on.
+///
+/// `start` and `end` are byte offsets into the file, `line` and `column`
+/// count from one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Span {
+    pub file: Rc<PathBuf>,
+    pub line: usize,
+    pub column: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    /// An empty span at the very start of `file`.
+    pub fn start_of(file: Rc<PathBuf>) -> Self {
+        Span {
+            file,
+            line: 1,
+            column: 1,
+            start: 0,
+            end: 0,
+        }
+    }
+
+    /// The span from the start of `self` to the end of `other`.
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            end: other.end.max(self.start),
+            ..self.clone()
+        }
+    }
+}
+
+/// Spans are written without their file, which is given once for the whole
+/// document.
+impl Serialize for Span {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("Span", 4)?;
+        s.serialize_field("line", &self.line)?;
+        s.serialize_field("column", &self.column)?;
+        s.serialize_field("start", &self.start)?;
+        s.serialize_field("end", &self.end)?;
+        s.end()
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file.display(), self.line, self.column)
+    }
+}
+
+/// A value along with the source it came from.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Spanned<T> {
+    pub node: T,
+    pub span: Span,
+}
+
+impl<T> Deref for Spanned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.node
+    }
+}
@@ -1,34 +1,132 @@
-use crate::{lexer, parser::*};
+use crate::{
+    builtin,
+    cexpr::CExpr,
+    check,
+    error::{CarpnError, CodegenError},
+    lexer::{self, NumSuffix},
+    parser::*,
+    span::{Span, Spanned},
+};
 use std::cell::RefCell;
+use std::fmt;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
 use std::collections::HashMap;
 
 #[derive(Clone, Debug)]
 pub struct ProcData {
-    full_name: String,
-    args: u8,
-    rets: bool,
-    ret_kind: String,
+    pub full_name: String,
+    pub args: Vec<String>,
+    /// the names of the named parameters, by position
+    pub names: Vec<Option<String>>,
+    /// the result types, in the order they are pushed
+    pub results: Vec<String>,
+    /// the C return type, a result struct when there are several results
+    pub ret_kind: String,
+    pub is_extern: bool,
 }
 
 #[derive(Clone, Debug)]
 pub enum StackEntry {
     Type(String),
-    Value(String, HashMap<String, StackEntry>),
-    Struct(String, VisitableCtx),
-    Field(String, HashMap<String, StackEntry>),
+    Value(CExpr, String, HashMap<String, StackEntry>),
+    Struct(String, Box<VisitableCtx>),
+    Field(String, String, HashMap<String, StackEntry>),
     SelfType,
 }
 
+impl StackEntry {
+    /// How the entry reads in an error message.
+    fn describe(&self) -> String {
+        match self {
+            StackEntry::Type(t) => format!("type `{}`", t),
+            StackEntry::Value(v, kind, _) => format!("value `{}` of type `{}`", v, kind),
+            StackEntry::Struct(name, _) => format!("struct `{}`", name),
+            StackEntry::Field(name, _, _) => format!("field `{}`", name),
+            StackEntry::SelfType => "`Self`".to_string(),
+        }
+    }
+
+    /// Whether two entries that aren't values name the same thing, so
+    /// either can stand for both after an `if`.
+    fn same_as(&self, other: &StackEntry) -> bool {
+        match (self, other) {
+            (StackEntry::Type(a), StackEntry::Type(b)) => a == b,
+            (StackEntry::Struct(a, _), StackEntry::Struct(b, _)) => a == b,
+            (StackEntry::Field(a, a_kind, _), StackEntry::Field(b, b_kind, _)) => {
+                a == b && a_kind == b_kind
+            }
+            (StackEntry::SelfType, StackEntry::SelfType) => true,
+            _ => false,
+        }
+    }
+}
+
+/// A short form of the entry for `--dump stack`.
+impl fmt::Display for StackEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StackEntry::Type(t) => write!(f, "type {}", t),
+            StackEntry::Value(v, kind, _) => write!(f, "{}: {}", v, kind),
+            StackEntry::Struct(name, _) => write!(f, "struct {}", name),
+            StackEntry::Field(name, _, _) => write!(f, "field {}", name),
+            StackEntry::SelfType => write!(f, "Self"),
+        }
+    }
+}
+
+pub type CodegenResult<T> = Result<T, CarpnError>;
+
+/// What opened a scope.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScopeKind {
+    File,
+    Struct,
+    Proc,
+    /// the body of an `if`, `else`, `while` or `loop`
+    Block,
+}
+
+impl fmt::Display for ScopeKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ScopeKind::File => write!(f, "file"),
+            ScopeKind::Struct => write!(f, "struct"),
+            ScopeKind::Proc => write!(f, "proc"),
+            ScopeKind::Block => write!(f, "block"),
+        }
+    }
+}
+
+/// The names declared in one scope, each with where it was declared.
+#[derive(Clone, Debug)]
+pub struct Scope {
+    pub kind: ScopeKind,
+    pub vars: HashMap<String, (StackEntry, Span)>,
+}
+
+impl Scope {
+    pub fn new(kind: ScopeKind) -> Self {
+        Scope {
+            kind,
+            vars: HashMap::new(),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct VisitableCtx {
     pub stack: Rc<RefCell<Vec<StackEntry>>>,
-    pub vars: HashMap<String, StackEntry>,
+    /// the scopes around the source being generated, innermost last
+    pub scopes: Vec<Scope>,
+    /// names whose scope has ended, for errors
+    pub ended: HashMap<String, (ScopeKind, Span)>,
     pub procs: HashMap<String, ProcData>,
+    pub defs: HashMap<String, Def>,
+    pub expanding: Vec<String>,
     pub var_idx: usize,
     pub indent: Rc<RefCell<usize>>,
     pub inside: String,
@@ -37,92 +135,871 @@ pub struct VisitableCtx {
     pub h_file: String,
     pub cache: PathBuf,
     pub c_files: Vec<String>,
+    /// the source being generated, for errors
+    pub span: Span,
+    /// the stack after each word, when it is being dumped
+    pub trace: Option<Rc<RefCell<Vec<String>>>>,
+    /// the proc being generated
+    pub current: Option<ProcData>,
 }
 
 impl VisitableCtx {
     pub fn ind(&self) -> String {
         "\n".to_string() + &" ".repeat(*self.indent.borrow_mut() * 4)
     }
+
+    /// Declares the names every file starts with: `void`, `null` and the
+    /// number types.
+    pub fn declare_prelude(&mut self) {
+        self.declare("void", StackEntry::Type("void".to_string()));
+        self.declare(
+            "null",
+            StackEntry::Value(
+                CExpr::cast("void*", CExpr::atom("0")),
+                "void*".to_string(),
+                HashMap::new(),
+            ),
+        );
+        for s in NumSuffix::ALL {
+            self.declare(s.name(), StackEntry::Type(s.c_type().to_string()));
+        }
+    }
+
+    /// A context for generating `test.car`, with nothing declared yet.
+    #[cfg(test)]
+    pub fn for_test() -> Self {
+        let file = Rc::new(PathBuf::from("test.car"));
+
+        VisitableCtx {
+            stack: Rc::new(RefCell::new(Vec::new())),
+            scopes: vec![Scope::new(ScopeKind::File)],
+            ended: HashMap::new(),
+            procs: HashMap::new(),
+            defs: HashMap::new(),
+            expanding: Vec::new(),
+            inside: "".to_string(),
+            in_struct: None,
+            var_idx: 0,
+            indent: Rc::new(RefCell::new(0)),
+            in_proc: false,
+            h_file: "test.h".to_string(),
+            cache: PathBuf::new(),
+            c_files: Vec::new(),
+            span: Span::start_of(file),
+            trace: None,
+            current: None,
+        }
+    }
+
+    /// An error pointing at the source being generated.
+    pub fn error(&self, error: CodegenError) -> CarpnError {
+        CarpnError::Codegen(Spanned {
+            node: error,
+            span: self.span.clone(),
+        })
+    }
+
+    /// Opens a scope, names declared until the matching `pop_scope` shadow
+    /// the ones outside it.
+    pub fn push_scope(&mut self, kind: ScopeKind) {
+        self.scopes.push(Scope::new(kind));
+    }
+
+    /// Closes the innermost scope, forgetting the names declared in it.
+    pub fn pop_scope(&mut self) {
+        let Some(scope) = self.scopes.pop() else {
+            return;
+        };
+
+        for (name, (_, span)) in scope.vars {
+            self.ended.insert(name, (scope.kind, span));
+        }
+    }
+
+    /// Declares `name` in the innermost scope, at the source being
+    /// generated.
+    pub fn declare(&mut self, name: &str, entry: StackEntry) {
+        let span = self.span.clone();
+
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.vars.insert(name.to_string(), (entry, span));
+        }
+    }
+
+    /// What `name` refers to in the innermost scope that declares it.
+    pub fn lookup(&self, name: &str) -> Option<&StackEntry> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|s| s.vars.get(name))
+            .map(|(entry, _)| entry)
+    }
+
+    pub fn lookup_mut(&mut self, name: &str) -> Option<&mut StackEntry> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .find_map(|s| s.vars.get_mut(name))
+            .map(|(entry, _)| entry)
+    }
+
+    /// The names declared in the innermost scope, which for a struct are
+    /// its fields.
+    pub fn fields(&self) -> HashMap<String, StackEntry> {
+        self.scopes
+            .last()
+            .map(|s| {
+                s.vars
+                    .iter()
+                    .map(|(name, (entry, _))| (name.clone(), entry.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// The error for a use of `name` that found nothing, which says so
+    /// when `name` was declared in a scope that has since ended.
+    pub fn unknown(&self, name: &str) -> CarpnError {
+        match self.ended.get(name) {
+            Some((kind, span)) => self.error(CodegenError::OutOfScope {
+                name: name.to_string(),
+                kind: kind.to_string(),
+                declared: span.to_string(),
+            }),
+            None => self.error(CodegenError::UnknownIdentifier(name.to_string())),
+        }
+    }
+
+    /// Pops the top entry for `word`.
+    pub fn pop(&self, word: &str) -> CodegenResult<StackEntry> {
+        let top = self.stack.borrow_mut().pop();
+
+        top.ok_or_else(|| {
+            self.error(CodegenError::Underflow {
+                word: word.to_string(),
+                needed: 1,
+                found: 0,
+            })
+        })
+    }
+
+    /// Pops a value for `word`, giving its C expression, type and fields.
+    pub fn pop_value(
+        &self,
+        word: &str,
+    ) -> CodegenResult<(CExpr, String, HashMap<String, StackEntry>)> {
+        match self.pop(word)? {
+            StackEntry::Value(v, kind, fields) => Ok((v, kind, fields)),
+            top => Err(self.error(CodegenError::ExpectedValueFoundType {
+                word: word.to_string(),
+                found: top.describe(),
+            })),
+        }
+    }
+
+    /// Pops a type for `word`.
+    pub fn pop_type(&self, word: &str) -> CodegenResult<String> {
+        match self.pop(word)? {
+            StackEntry::Type(t) => Ok(t),
+            top => Err(self.error(CodegenError::ExpectedTypeFoundValue {
+                word: word.to_string(),
+                found: top.describe(),
+            })),
+        }
+    }
 }
 
-pub trait Visitable {
-    fn header(&self, ctx: &mut VisitableCtx) -> String {
-        "".to_string()
+/// The C expression a `return` gives back, popped from the stack for
+/// `word`. A proc with several results builds its result struct from that
+/// many values.
+fn return_value(ctx: &VisitableCtx, word: &str) -> CodegenResult<CExpr> {
+    let Some(data) = ctx.current.as_ref().filter(|d| d.results.len() > 1) else {
+        return Ok(ctx.pop_value(word)?.0);
+    };
+
+    let mut values = Vec::new();
+    for _ in &data.results {
+        values.push(ctx.pop_value(word)?.0.to_string());
     }
-    fn source(&self, ctx: &mut VisitableCtx) -> String {
-        "".to_string()
+    values.reverse();
+
+    Ok(CExpr::atom(format!(
+        "({}){{{}}}",
+        data.ret_kind,
+        values.join(", ")
+    )))
+}
+
+/// The typedef of the struct that carries several results back from a
+/// proc, one `ret_N` field to a result.
+fn result_struct(data: &ProcData, ctx: &VisitableCtx) -> String {
+    let mut result = "typedef struct {".to_string();
+    *ctx.indent.borrow_mut() += 1;
+
+    for (i, kind) in data.results.iter().enumerate() {
+        result += &ctx.ind();
+        result += &format!("{} ret_{};", kind, i);
     }
+
+    *ctx.indent.borrow_mut() -= 1;
+    result += &ctx.ind();
+    result += &format!("}} {};", data.ret_kind);
+    result += &ctx.ind();
+
+    result
 }
 
-impl Visitable for Expression {
-    fn source(&self, ctx: &mut VisitableCtx) -> String {
+pub fn write_file(path: &Path, conts: &str) -> CodegenResult<()> {
+    File::create(path)
+        .and_then(|mut file| file.write_all(conts.as_bytes()))
+        .map_err(|e| CarpnError::io(path, e))
+}
+
+/// Spells a carpn identifier as a C one. Names that are already valid C are
+/// kept as they are, punctuation is written as its hex code and a leading
+/// digit gets a `_` in front.
+fn c_ident(name: &str) -> String {
+    let mut result = "".to_string();
+
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        result.push('_');
+    }
+
+    for c in name.chars() {
+        if c.is_alphanumeric() || c == '_' {
+            result.push(c);
+        } else {
+            result += &format!("_{:x}", c as u32);
+        }
+    }
+
+    result
+}
+
+/// Words C keeps for itself, up to C23.
+const C_KEYWORDS: &[&str] = &[
+    "alignas",
+    "alignof",
+    "auto",
+    "bool",
+    "break",
+    "case",
+    "char",
+    "const",
+    "constexpr",
+    "continue",
+    "default",
+    "do",
+    "double",
+    "else",
+    "enum",
+    "extern",
+    "false",
+    "float",
+    "for",
+    "goto",
+    "if",
+    "inline",
+    "int",
+    "long",
+    "nullptr",
+    "register",
+    "restrict",
+    "return",
+    "short",
+    "signed",
+    "sizeof",
+    "static",
+    "static_assert",
+    "struct",
+    "switch",
+    "thread_local",
+    "true",
+    "typedef",
+    "typeof",
+    "typeof_unqual",
+    "union",
+    "unsigned",
+    "void",
+    "volatile",
+    "while",
+];
+
+/// The C name of a named parameter. It keeps the name it has in carpn, unless
+/// that is a C keyword, is reserved by C, or could be one of the names the
+/// generated code uses for its own locals. Those get a `p_` in front, and so
+/// does any name already starting with one, so two parameters never meet.
+fn param_ident(name: &str) -> String {
+    let ident = c_ident(name);
+
+    let reserved = ident
+        .strip_prefix('_')
+        .is_some_and(|rest| rest.starts_with(|c: char| c == '_' || c.is_ascii_uppercase()));
+    let generated = ident.ends_with("_result")
+        || ident.split_once('_').is_some_and(|(prefix, rest)| {
+            prefix == "p"
+                || ["anon", "arg", "field", "if", "loop", "next", "ret", "var"].contains(&prefix)
+                    && rest.starts_with(|c: char| c.is_ascii_digit())
+        });
+
+    if C_KEYWORDS.contains(&ident.as_str()) || reserved || generated {
+        format!("p_{}", ident)
+    } else {
+        ident
+    }
+}
+
+/// Writes `byte` as it goes inside a C literal quoted with `quote`. Anything
+/// that is not printable ascii is spelled as an octal escape, which unlike a
+/// hex one cannot run on into the characters after it.
+fn c_escape(byte: u8, quote: u8) -> String {
+    match byte {
+        b'\n' => "\\n".to_string(),
+        b'\t' => "\\t".to_string(),
+        b'\r' => "\\r".to_string(),
+        b'\\' => "\\\\".to_string(),
+        b if b == quote => format!("\\{}", b as char),
+        b' '..=b'~' => (byte as char).to_string(),
+        _ => format!("\\{:03o}", byte),
+    }
+}
+
+/// A C string literal holding `s`, encoded as utf-8.
+fn c_string(s: &str) -> String {
+    let body: String = s.bytes().map(|b| c_escape(b, b'"')).collect();
+
+    format!("\"{}\"", body)
+}
+
+/// A C character literal, `c` is always ascii.
+fn c_char(c: char) -> String {
+    format!("'{}'", c_escape(c as u8, b'\''))
+}
+
+/// A doc comment carried over to the generated header.
+fn doc_comment(doc: &Option<String>, ctx: &VisitableCtx) -> String {
+    let Some(doc) = doc else {
+        return "".to_string();
+    };
+
+    let mut result = "/**".to_string();
+    for line in doc.lines() {
+        result += &ctx.ind();
+        result += format!(" * {}", line).trim_end();
+    }
+    result += &ctx.ind();
+    result += " */";
+    result += &ctx.ind();
+
+    result
+}
+
+/// A `#line` directive pointing the C compiler at `span`.
+fn line_directive(span: &Span) -> String {
+    format!("#line {} {:?}", span.line, span.file.display().to_string())
+}
+
+/// Generates each expression in `body`, marking the statements it produces
+/// with `#line` so the C compiler reports errors against the `.car` source.
+fn body_source(body: &[Spanned<Expression>], ctx: &mut VisitableCtx) -> CodegenResult<String> {
+    let mut result = "".to_string();
+
+    for b in body {
+        let outer = std::mem::replace(&mut ctx.span, b.span.clone());
+        let code = b.source(ctx)?;
+        ctx.span = outer;
+
+        if let Some(trace) = &ctx.trace {
+            let stack: Vec<String> = ctx.stack.borrow().iter().map(|e| e.to_string()).collect();
+
+            trace
+                .borrow_mut()
+                .push(format!("{}  {}  [{}]", b.span, b.node, stack.join(", ")));
+        }
+
+        if !code.trim().is_empty() {
+            result += &line_directive(&b.span);
+            result += &ctx.ind();
+        }
+
+        result += &code;
+    }
+
+    Ok(result)
+}
+
+/// Emits a C loop around `body`, breaking when `cond` (if any) leaves a
+/// false value.
+///
+/// Values on the stack are copied into `loop_N` locals before the loop so
+/// that each iteration can update them. The checker has already made sure
+/// every iteration leaves the stack as deep as it found it.
+fn loop_source(
+    cond: Option<&[Spanned<Expression>]>,
+    body: &[Spanned<Expression>],
+    ctx: &mut VisitableCtx,
+) -> CodegenResult<String> {
+    let mut result = "".to_string();
+
+    for entry in ctx.stack.borrow_mut().iter_mut() {
+        let StackEntry::Value(v, kind, fields) = entry else {
+            continue;
+        };
+
+        // addresses of locals never change between iterations
+        if v.addressed().is_some() {
+            continue;
+        }
+
+        let name = format!("loop_{}", ctx.var_idx);
+        ctx.var_idx += 1;
+
+        result += &format!("{} {} = {};", kind, name, v);
+        result += &ctx.ind();
+
+        *entry = StackEntry::Value(CExpr::atom(name), kind.clone(), fields.clone());
+    }
+
+    let entry_stack = ctx.stack.borrow().clone();
+
+    result += "while (1) {";
+    *ctx.indent.borrow_mut() += 1;
+    result += &ctx.ind();
+    ctx.push_scope(ScopeKind::Block);
+
+    if let Some(cond) = cond {
+        result += &body_source(cond, ctx)?;
+
+        let (c, _, _) = ctx.pop_value("while")?;
+
+        let updates = loop_updates(&entry_stack, ctx, "while condition")?;
+        if !updates.is_empty() {
+            return Err(ctx.error(CodegenError::ConditionChangedStack));
+        }
+
+        result += &format!("if ({}) break;", CExpr::unary("!", c));
+        result += &ctx.ind();
+    }
+
+    result += &body_source(body, ctx)?;
+
+    let updates = loop_updates(&entry_stack, ctx, "loop body")?;
+
+    for (i, (_, kind, new)) in updates.iter().enumerate() {
+        result += &format!("{} next_{}_{} = {};", kind, ctx.var_idx, i, new);
+        result += &ctx.ind();
+    }
+
+    for (i, (name, _, _)) in updates.iter().enumerate() {
+        result += &format!("{} = next_{}_{};", name, ctx.var_idx, i);
+        result += &ctx.ind();
+    }
+
+    if !updates.is_empty() {
+        ctx.var_idx += 1;
+    }
+
+    result = result.trim_end().to_string();
+
+    ctx.pop_scope();
+    *ctx.indent.borrow_mut() -= 1;
+    result += &ctx.ind();
+    result += "}";
+    result += &ctx.ind();
+
+    *ctx.stack.borrow_mut() = entry_stack;
+
+    Ok(result)
+}
+
+/// Checks the stack against its entries on loop entry, returning the
+/// `(local, type, new value)` of every carried value that changed.
+fn loop_updates(
+    entry_stack: &[StackEntry],
+    ctx: &VisitableCtx,
+    what: &str,
+) -> CodegenResult<Vec<(CExpr, String, CExpr)>> {
+    let stack = ctx.stack.borrow();
+
+    let replaced = |value: &CExpr| {
+        ctx.error(CodegenError::LoopReplacedValue {
+            what: what.to_string(),
+            value: value.to_string(),
+        })
+    };
+
+    let mut updates = Vec::new();
+
+    for (old, new) in entry_stack.iter().zip(stack.iter()) {
+        let StackEntry::Value(old, kind, _) = old else {
+            continue;
+        };
+
+        let StackEntry::Value(new, _, _) = new else {
+            return Err(replaced(old));
+        };
+
+        if old == new {
+            continue;
+        }
+
+        if old.addressed().is_some() {
+            return Err(replaced(old));
+        }
+
+        updates.push((old.clone(), kind.clone(), new.clone()));
+    }
+
+    Ok(updates)
+}
+
+fn is_float(kind: &str) -> bool {
+    kind == "float" || kind == "double"
+}
+
+/// Works out the type of `a op b`, refusing to silently mix integer and
+/// floating point operands; those need an explicit `'` cast.
+fn arith_kind(ctx: &VisitableCtx, op: &str, a: &str, b: &str) -> CodegenResult<String> {
+    if is_float(a) != is_float(b) {
+        return Err(ctx.error(CodegenError::MixedNumbers {
+            op: op.to_string(),
+            a: a.to_string(),
+            b: b.to_string(),
+        }));
+    }
+
+    if is_float(a) && b == "double" {
+        Ok(b.to_string())
+    } else {
+        Ok(a.to_string())
+    }
+}
+
+/// How an operator treats the types of its operands.
+enum OpKind {
+    /// numeric operands of one family, giving a value of the same type
+    Arith,
+    /// integer operands only
+    Integer,
+    /// numeric operands of one family, giving a truth value
+    Compare,
+    /// truth values of any type
+    Logic,
+}
+
+impl OpKind {
+    fn result(&self, ctx: &VisitableCtx, op: &str, a: &str, b: &str) -> CodegenResult<String> {
         match self {
-            Expression::Ident(i) if i == "disc" => {
-                _ = ctx.stack.borrow_mut().pop();
-                "".to_string()
+            OpKind::Arith => arith_kind(ctx, op, a, b),
+            OpKind::Integer => {
+                if is_float(a) || is_float(b) {
+                    return Err(ctx.error(CodegenError::IntegerOperands {
+                        op: op.to_string(),
+                        a: a.to_string(),
+                        b: b.to_string(),
+                    }));
+                }
+                arith_kind(ctx, op, a, b)
             }
-            Expression::Ident(i) if i == "Self" => {
-                ctx.stack.borrow_mut().push(StackEntry::SelfType);
-                "".to_string()
+            OpKind::Compare => {
+                arith_kind(ctx, op, a, b)?;
+                Ok("int".to_string())
             }
-            Expression::Ident(i) if i == "swap" => {
-                let Some(a) = ctx.stack.borrow_mut().pop() else {
-                    todo!()
-                };
-                let Some(b) = ctx.stack.borrow_mut().pop() else {
-                    todo!()
-                };
-                ctx.stack.borrow_mut().push(a);
-                ctx.stack.borrow_mut().push(b);
+            OpKind::Logic => Ok("int".to_string()),
+        }
+    }
+}
+
+/// Pops `a b` and pushes `a op b`.
+fn binary_op(ctx: &mut VisitableCtx, op: &'static str, kind: OpKind) -> CodegenResult<String> {
+    let (b, b_kind, _) = ctx.pop_value(op)?;
+    let (a, a_kind, _) = ctx.pop_value(op)?;
+    let kind = kind.result(ctx, op, &a_kind, &b_kind)?;
+
+    ctx.stack.borrow_mut().push(StackEntry::Value(
+        CExpr::binary(op, a, b),
+        kind,
+        HashMap::new(),
+    ));
+
+    Ok("".to_string())
+}
+
+/// Pops `a` and pushes `op a`.
+fn unary_op(ctx: &mut VisitableCtx, op: &'static str, kind: OpKind) -> CodegenResult<String> {
+    let (a, a_kind, _) = ctx.pop_value(op)?;
+    let kind = kind.result(ctx, op, &a_kind, &a_kind)?;
+
+    ctx.stack
+        .borrow_mut()
+        .push(StackEntry::Value(CExpr::unary(op, a), kind, HashMap::new()));
+
+    Ok("".to_string())
+}
+
+/// Applies C's default argument promotion for floats, which is what
+/// variadic externs like `printf` expect to receive.
+fn promote(value: CExpr, kind: &str) -> CExpr {
+    if kind == "float" {
+        CExpr::cast("double", value)
+    } else {
+        value
+    }
+}
+
+/// C has no negative literals, so `-5` is written as `-` applied to `5`.
+fn negate_literal(value: CExpr, neg: bool) -> CExpr {
+    if neg {
+        CExpr::unary("-", value)
+    } else {
+        value
+    }
+}
+
+struct Branch {
+    code: String,
+    stack: Vec<StackEntry>,
+    diverges: bool,
+}
+
+/// Generates one arm of an `if`, starting from a copy of `entry_stack`.
+fn branch_source(
+    body: &[Spanned<Expression>],
+    entry_stack: &[StackEntry],
+    ctx: &mut VisitableCtx,
+) -> CodegenResult<Branch> {
+    *ctx.stack.borrow_mut() = entry_stack.to_vec();
+    *ctx.indent.borrow_mut() += 1;
+    ctx.push_scope(ScopeKind::Block);
+
+    let code = body_source(body, ctx)?;
+
+    ctx.pop_scope();
+    *ctx.indent.borrow_mut() -= 1;
+
+    Ok(Branch {
+        code,
+        stack: ctx.stack.borrow().clone(),
+        diverges: diverges(body),
+    })
+}
+
+/// A local that carries a value out of the arms of an `if`, either because
+/// they disagree on it or because it may name locals declared in one arm.
+/// An arm that never falls through assigns nothing.
+struct BranchTemp {
+    name: String,
+    kind: String,
+    then_value: Option<CExpr>,
+    else_value: Option<CExpr>,
+}
+
+impl BranchTemp {
+    fn new(kind: String, ctx: &mut VisitableCtx) -> Self {
+        let name = format!("if_{}", ctx.var_idx);
+        ctx.var_idx += 1;
+
+        BranchTemp {
+            name,
+            kind,
+            then_value: None,
+            else_value: None,
+        }
+    }
+}
+
+/// Carries the stack left by the only arm of an `if` that falls through
+/// past the `if`.
+///
+/// Values the arm computed are replaced by `if_N` locals, as they may use C
+/// locals declared inside its block. Values from before the `if` and
+/// literals are kept as they are.
+fn hoist_branch(
+    stack: Vec<StackEntry>,
+    entry_stack: &[StackEntry],
+    is_then: bool,
+    ctx: &mut VisitableCtx,
+) -> (Vec<StackEntry>, Vec<BranchTemp>) {
+    let mut hoisted = Vec::new();
+    let mut temps = Vec::new();
+
+    for (i, entry) in stack.into_iter().enumerate() {
+        let StackEntry::Value(value, kind, fields) = entry else {
+            hoisted.push(entry);
+            continue;
+        };
+
+        let unchanged =
+            matches!(entry_stack.get(i), Some(StackEntry::Value(v, _, _)) if *v == value);
+        if unchanged || value.is_constant() {
+            hoisted.push(StackEntry::Value(value, kind, fields));
+            continue;
+        }
+
+        let mut temp = BranchTemp::new(kind.clone(), ctx);
+        hoisted.push(StackEntry::Value(
+            CExpr::atom(temp.name.clone()),
+            kind,
+            fields,
+        ));
+        if is_then {
+            temp.then_value = Some(value);
+        } else {
+            temp.else_value = Some(value);
+        }
+        temps.push(temp);
+    }
 
-                "".to_string()
+    (hoisted, temps)
+}
+
+/// Joins the stacks left by the two arms of an `if`, which the checker has
+/// already made sure are as deep as each other.
+///
+/// Values that differ between the arms are replaced by `if_N` locals, which
+/// are returned so the caller can declare and assign them.
+fn merge_branches(
+    then_stack: Vec<StackEntry>,
+    else_stack: Vec<StackEntry>,
+    ctx: &mut VisitableCtx,
+) -> CodegenResult<(Vec<StackEntry>, Vec<BranchTemp>)> {
+    let mut merged = Vec::new();
+    let mut temps = Vec::new();
+
+    for (a, b) in then_stack.into_iter().zip(else_stack) {
+        match (a, b) {
+            (StackEntry::Value(a, kind, fields), StackEntry::Value(b, b_kind, _)) => {
+                if a == b {
+                    merged.push(StackEntry::Value(a, kind, fields));
+                    continue;
+                }
+
+                if kind != b_kind {
+                    return Err(ctx.error(CodegenError::BranchTypes(kind, b_kind)));
+                }
+
+                let mut temp = BranchTemp::new(kind.clone(), ctx);
+                merged.push(StackEntry::Value(
+                    CExpr::atom(temp.name.clone()),
+                    kind,
+                    fields,
+                ));
+                temp.then_value = Some(a);
+                temp.else_value = Some(b);
+                temps.push(temp);
             }
-            Expression::Ident(i) if i == "copy" => {
-                let Some(tmp) = ctx.stack.borrow_mut().pop() else {
-                    todo!()
-                };
-                ctx.stack.borrow_mut().push(tmp.clone());
-                ctx.stack.borrow_mut().push(tmp.clone());
-                "".to_string()
+            (StackEntry::Value(a, _, _), _) | (_, StackEntry::Value(a, _, _)) => {
+                return Err(ctx.error(CodegenError::BranchValue(a.to_string())));
+            }
+            (a, b) => {
+                if !a.same_as(&b) {
+                    return Err(ctx.error(CodegenError::BranchEntries(a.describe(), b.describe())));
+                }
+
+                merged.push(a);
+            }
+        }
+    }
+
+    Ok((merged, temps))
+}
+
+pub trait Visitable {
+    fn header(&self, _ctx: &mut VisitableCtx) -> CodegenResult<String> {
+        Ok("".to_string())
+    }
+    fn source(&self, _ctx: &mut VisitableCtx) -> CodegenResult<String> {
+        Ok("".to_string())
+    }
+}
+
+impl Visitable for Expression {
+    fn source(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
+        // builtin words can't be redefined, see `builtin::reserved`
+        if let Expression::Ident(i) = self {
+            if let Some(word) = builtin::find(i) {
+                // a type on top would make this a declaration, as in `i32 tuck`
+                let top = ctx.stack.borrow().last().cloned();
+                if let Some(StackEntry::Type(_) | StackEntry::Struct(..)) = top {
+                    let what = if ctx.in_proc { "local" } else { "field" };
+                    builtin::reserved(i, what).map_err(|e| ctx.error(e))?;
+                }
+                return word.source(ctx);
+            }
+        }
+
+        match self {
+            Expression::Ident(i) if ctx.defs.contains_key(i) => {
+                if ctx.expanding.contains(i) {
+                    return Err(ctx.error(CodegenError::RecursiveDef(i.clone())));
+                }
+
+                let def = ctx.defs[i].clone();
+
+                ctx.expanding.push(i.clone());
+                let result = body_source(&def.body, ctx);
+                ctx.expanding.pop();
+
+                result
             }
             Expression::Ident(i) => {
-                if let Some(pushes) = ctx.vars.get(i) {
+                if let Some(pushes) = ctx.lookup(i) {
                     ctx.stack.borrow_mut().push(pushes.clone());
-                    "".to_string()
+                    Ok("".to_string())
                 } else if let Some(proc) = ctx.procs.get(i) {
                     let mut args = Vec::new();
 
-                    for a in 0..proc.args {
-                        args.push(ctx.stack.borrow_mut().pop().unwrap());
+                    for _a in 0..proc.args.len() {
+                        args.push(ctx.pop_value(i)?);
                     }
 
                     let mut call = format!("{}(", proc.full_name);
                     let mut add = false;
-                    while let Some(a) = args.pop() {
-                        let StackEntry::Value(a, _) = a else { todo!() };
+                    for (arg_kind, (a, kind, _)) in proc.args.iter().zip(args.into_iter().rev()) {
                         if add {
                             call += ",";
                         }
                         add = true;
-                        call += &a;
+
+                        if proc.is_extern {
+                            call += &promote(a, &kind).to_string();
+                        } else {
+                            arith_kind(ctx, &format!("a call to {}", i), arg_kind, &kind)?;
+                            call += &a.to_string();
+                        }
                     }
 
                     call += ");";
 
                     call += &ctx.ind();
 
-                    if proc.rets {
-                        ctx.stack.borrow_mut().push(StackEntry::Value(
-                            format!("anon_{}", ctx.var_idx),
-                            HashMap::new(),
-                        ));
-
-                        call = format!("{} anon_{} = {}", proc.ret_kind, ctx.var_idx, call);
-
+                    if !proc.results.is_empty() {
+                        let anon = format!("anon_{}", ctx.var_idx);
                         ctx.var_idx += 1;
+
+                        call = format!("{} {} = {}", proc.ret_kind, anon, call);
+
+                        // several results come back in a struct, one field each
+                        for (i, kind) in proc.results.iter().enumerate() {
+                            let value = if proc.results.len() == 1 {
+                                CExpr::atom(anon.clone())
+                            } else {
+                                CExpr::Member(
+                                    Box::new(CExpr::atom(anon.clone())),
+                                    ".",
+                                    format!("ret_{}", i),
+                                )
+                            };
+
+                            ctx.stack.borrow_mut().push(StackEntry::Value(
+                                value,
+                                kind.clone(),
+                                HashMap::new(),
+                            ));
+                        }
                     }
 
-                    call
+                    Ok(call)
                 } else {
                     let top = ctx.stack.borrow_mut().pop();
 
@@ -134,25 +1011,29 @@ impl Visitable for Expression {
                             let mut var = format!("{} {};", k, var_name);
                             var += &ctx.ind();
 
-                            ctx.vars.insert(
-                                i.clone(),
-                                StackEntry::Value(format!("&{}", var_name), HashMap::new()),
+                            ctx.declare(
+                                i,
+                                StackEntry::Value(
+                                    CExpr::address(var_name),
+                                    format!("{}*", k),
+                                    HashMap::new(),
+                                ),
                             );
 
-                            return var;
+                            return Ok(var);
                         } else {
                             let field_name = format!("field_{}", ctx.var_idx);
                             ctx.var_idx += 1;
 
-                            ctx.vars.insert(
-                                i.clone(),
-                                StackEntry::Field(field_name.clone(), HashMap::new()),
+                            ctx.declare(
+                                i,
+                                StackEntry::Field(field_name.clone(), k.clone(), HashMap::new()),
                             );
 
                             let mut var = format!("{} {};", k, field_name);
                             var += &ctx.ind();
 
-                            return var;
+                            return Ok(var);
                         }
                     } else if let Some(StackEntry::Struct(name, sctx)) = top {
                         if ctx.in_proc {
@@ -162,329 +1043,499 @@ impl Visitable for Expression {
                             let mut var = format!("{} {};", name, var_name);
                             var += &ctx.ind();
 
-                            ctx.vars.insert(
-                                i.clone(),
-                                StackEntry::Value(format!("&{}", var_name), sctx.vars.clone()),
+                            ctx.declare(
+                                i,
+                                StackEntry::Value(
+                                    CExpr::address(var_name),
+                                    format!("{}*", name),
+                                    sctx.fields(),
+                                ),
                             );
 
-                            return var;
+                            return Ok(var);
                         } else {
                             let field_name = format!("field_{}", ctx.var_idx);
                             ctx.var_idx += 1;
 
-                            ctx.vars.insert(
-                                i.clone(),
-                                StackEntry::Field(field_name.clone(), sctx.vars.clone()),
+                            ctx.declare(
+                                i,
+                                StackEntry::Field(field_name.clone(), name.clone(), sctx.fields()),
                             );
 
                             let mut var = format!("{} {};", name, field_name);
                             var += &ctx.ind();
 
-                            return var;
+                            return Ok(var);
                         }
-                    };
+                    }
 
-                    todo!("err: {}", i)
+                    Err(ctx.unknown(i))
                 }
             }
             Expression::Prop(p) => {
-                let mut top = ctx.stack.borrow_mut().pop().unwrap();
+                let mut top = ctx.pop(&format!(".{}", p))?;
 
-                println!("{:?}", p);
+                let unknown = |ctx: &VisitableCtx, top: &StackEntry| {
+                    ctx.error(CodegenError::UnknownField {
+                        field: p.clone(),
+                        found: top.describe(),
+                    })
+                };
 
                 match &mut top {
-                    StackEntry::Value(v, map) => match map.get(p) {
-                        Some(StackEntry::Field(f, map)) => ctx
-                            .stack
-                            .borrow_mut()
-                            .push(StackEntry::Value(format!("{}.{}", v, f), map.clone())),
-                        s => todo!("{:?}", s),
+                    StackEntry::Value(v, v_kind, map) => match map.get(p) {
+                        Some(StackEntry::Field(f, kind, map)) => {
+                            // a pointer to a struct gives a pointer to its field
+                            let field = match v.addressed() {
+                                Some(base) => CExpr::Member(Box::new(base.clone()), ".", f.clone()),
+                                None if v_kind.ends_with('*') => {
+                                    CExpr::Member(Box::new(v.clone()), "->", f.clone())
+                                }
+                                None => CExpr::Member(Box::new(v.clone()), ".", f.clone()),
+                            };
+                            let (field, kind) = if v_kind.ends_with('*') {
+                                (CExpr::unary("&", field), format!("{}*", kind))
+                            } else {
+                                (field, kind.clone())
+                            };
+
+                            ctx.stack
+                                .borrow_mut()
+                                .push(StackEntry::Value(field, kind, map.clone()))
+                        }
+                        _ => return Err(unknown(ctx, &top)),
                     },
                     StackEntry::Type(v) => ctx
                         .stack
                         .borrow_mut()
                         .push(StackEntry::Type(format!("{}_{}", v, p))),
-                    StackEntry::Struct(_, v) => return Expression::Ident(p.clone()).source(v),
+                    StackEntry::Struct(_, v) => {
+                        v.span = ctx.span.clone();
+                        return Expression::Ident(p.clone()).source(v);
+                    }
                     StackEntry::SelfType => return Expression::Ident(p.clone()).source(ctx),
-                    s => todo!("{:?}", s),
+                    StackEntry::Field(..) => return Err(unknown(ctx, &top)),
                 }
-                "".to_string()
+                Ok("".to_string())
             }
-            Expression::Op(ExprOp::GreaterThan) => {
-                let StackEntry::Value(b, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                let StackEntry::Value(a, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                ctx.stack
-                    .borrow_mut()
-                    .push(StackEntry::Value(format!("{} > {}", a, b), HashMap::new()));
-                "".to_string()
-            }
-            Expression::Op(ExprOp::LessThan) => {
-                let StackEntry::Value(b, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                let StackEntry::Value(a, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                ctx.stack
-                    .borrow_mut()
-                    .push(StackEntry::Value(format!("{} < {}", a, b), HashMap::new()));
-                "".to_string()
-            }
-            Expression::Op(ExprOp::Star) => {
-                let top = ctx.stack.borrow_mut().pop().unwrap();
-                match top {
-                    StackEntry::Type(top) => {
-                        let top = format!("{}*", top);
-                        ctx.stack.borrow_mut().push(StackEntry::Type(top));
-                        "".to_string()
-                    }
-                    _ => todo!(),
+            Expression::Op(ExprOp::GreaterThan) => binary_op(ctx, ">", OpKind::Compare),
+            Expression::Op(ExprOp::LessThan) => binary_op(ctx, "<", OpKind::Compare),
+            Expression::Op(ExprOp::Star) => match ctx.pop("*")? {
+                StackEntry::Type(top) => {
+                    let top = format!("{}*", top);
+                    ctx.stack.borrow_mut().push(StackEntry::Type(top));
+                    Ok("".to_string())
                 }
-            }
-            Expression::Op(ExprOp::Dollar) => {
-                let top = ctx.stack.borrow_mut().pop().unwrap();
-                match top {
-                    StackEntry::Type(top) => {
-                        let top = format!("{}", top);
-                        ctx.stack.borrow_mut().push(StackEntry::Type(top));
-                        "".to_string()
-                    }
-                    _ => todo!(),
+                top @ StackEntry::Value(..) => {
+                    ctx.stack.borrow_mut().push(top);
+                    binary_op(ctx, "*", OpKind::Arith)
                 }
+                top => Err(ctx.error(CodegenError::ExpectedValueFoundType {
+                    word: "*".to_string(),
+                    found: top.describe(),
+                })),
+            },
+            Expression::Op(ExprOp::Divide) => binary_op(ctx, "/", OpKind::Arith),
+            Expression::Op(ExprOp::Modulo) => binary_op(ctx, "%", OpKind::Integer),
+            Expression::Op(ExprOp::BitAnd) => binary_op(ctx, "&", OpKind::Integer),
+            Expression::Op(ExprOp::BitOr) => binary_op(ctx, "|", OpKind::Integer),
+            Expression::Op(ExprOp::BitXor) => binary_op(ctx, "^", OpKind::Integer),
+            Expression::Op(ExprOp::ShiftLeft) => binary_op(ctx, "<<", OpKind::Integer),
+            Expression::Op(ExprOp::ShiftRight) => binary_op(ctx, ">>", OpKind::Integer),
+            Expression::Op(ExprOp::LessEqual) => binary_op(ctx, "<=", OpKind::Compare),
+            Expression::Op(ExprOp::GreaterEqual) => binary_op(ctx, ">=", OpKind::Compare),
+            Expression::Op(ExprOp::NotEqual) => binary_op(ctx, "!=", OpKind::Compare),
+            Expression::Op(ExprOp::And) => binary_op(ctx, "&&", OpKind::Logic),
+            Expression::Op(ExprOp::Or) => binary_op(ctx, "||", OpKind::Logic),
+            Expression::Op(ExprOp::Not) => unary_op(ctx, "!", OpKind::Logic),
+            Expression::Op(ExprOp::Negate) => unary_op(ctx, "-", OpKind::Arith),
+            Expression::Op(ExprOp::Dollar) => {
+                let top = ctx.pop_type("$")?;
+                ctx.stack.borrow_mut().push(StackEntry::Type(top));
+                Ok("".to_string())
             }
-            Expression::Op(ExprOp::Equal) => {
-                let StackEntry::Value(a, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                let StackEntry::Value(b, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                ctx.stack
-                    .borrow_mut()
-                    .push(StackEntry::Value(format!("{} == {}", a, b), HashMap::new()));
-                "".to_string()
-            }
-            Expression::Op(ExprOp::Plus) => {
-                let StackEntry::Value(b, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                let StackEntry::Value(a, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                ctx.stack
-                    .borrow_mut()
-                    .push(StackEntry::Value(format!("{} + {}", a, b), HashMap::new()));
-                "".to_string()
-            }
+            Expression::Op(ExprOp::Equal) => binary_op(ctx, "==", OpKind::Compare),
+            Expression::Op(ExprOp::Plus) => binary_op(ctx, "+", OpKind::Arith),
             Expression::Op(ExprOp::Assign) => {
-                let StackEntry::Value(b, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                let StackEntry::Value(a, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                let mut assign = format!("*{} = {};", a, b);
-
-                if assign.starts_with("*&") {
-                    assign.remove(0);
-                    assign.remove(0);
-                }
-
+                let (b, _, _) = ctx.pop_value("=")?;
+                let (a, _, _) = ctx.pop_value("=")?;
+                let mut assign = format!("{} = {};", a.deref(), b);
                 assign += &ctx.ind();
 
-                assign
+                Ok(assign)
             }
             Expression::Op(ExprOp::Deref) => {
-                let StackEntry::Value(a, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
+                let (a, kind, _) = ctx.pop_value("@")?;
+                let kind = kind.strip_suffix('*').unwrap_or(&kind).to_string();
 
-                if a.starts_with('&') {
-                    let mut tmp = a.clone();
-                    tmp.remove(0);
-                    ctx.stack
-                        .borrow_mut()
-                        .push(StackEntry::Value(format!("{}", tmp), HashMap::new()));
-                } else {
-                    ctx.stack
-                        .borrow_mut()
-                        .push(StackEntry::Value(format!("*{}", a), HashMap::new()));
-                }
-                "".to_string()
-            }
-            Expression::Op(ExprOp::Minus) => {
-                let StackEntry::Value(b, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                let StackEntry::Value(a, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
                 ctx.stack
                     .borrow_mut()
-                    .push(StackEntry::Value(format!("{} - {}", a, b), HashMap::new()));
-                "".to_string()
+                    .push(StackEntry::Value(a.deref(), kind, HashMap::new()));
+                Ok("".to_string())
             }
+            Expression::Op(ExprOp::Minus) => binary_op(ctx, "-", OpKind::Arith),
             Expression::Op(ExprOp::Name(n)) => {
-                let Some(top) = ctx.stack.borrow_mut().pop() else {
-                    todo!()
-                };
+                let top = ctx.pop(&format!("=>{}", n))?;
 
-                ctx.vars.insert(n.clone(), top);
+                ctx.declare(n, top);
 
-                "".to_string()
+                Ok("".to_string())
             }
             Expression::Op(ExprOp::Tick) => {
-                let StackEntry::Type(to) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
-                let StackEntry::Value(top, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
-                };
+                let to = ctx.pop_type("'")?;
+                let (top, _, _) = ctx.pop_value("'")?;
                 ctx.stack.borrow_mut().push(StackEntry::Value(
-                    format!("({})({})", to, top),
+                    CExpr::cast(to.clone(), top),
+                    to,
                     HashMap::new(),
                 ));
-                "".to_string()
+                Ok("".to_string())
             }
             Expression::String(i) => {
-                ctx.stack
-                    .borrow_mut()
-                    .push(StackEntry::Value(format!("\"{}\"", i), HashMap::new()));
-                "".to_string()
+                ctx.stack.borrow_mut().push(StackEntry::Value(
+                    CExpr::atom(c_string(i)),
+                    "char*".to_string(),
+                    HashMap::new(),
+                ));
+                Ok("".to_string())
             }
-            Expression::Int(i) => {
-                ctx.stack
-                    .borrow_mut()
-                    .push(StackEntry::Value(i.to_string(), HashMap::new()));
-                "".to_string()
+            Expression::Char(c) => {
+                ctx.stack.borrow_mut().push(StackEntry::Value(
+                    CExpr::atom(c_char(*c)),
+                    "char".to_string(),
+                    HashMap::new(),
+                ));
+                Ok("".to_string())
             }
-            Expression::Return => {
-                let StackEntry::Value(result, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
+            Expression::Float(f, suffix) => {
+                let c_suffix = if *suffix == Some(NumSuffix::F32) {
+                    "f"
+                } else {
+                    ""
                 };
+                let value = CExpr::atom(format!("{:?}{}", f.abs(), c_suffix));
+
+                ctx.stack.borrow_mut().push(StackEntry::Value(
+                    negate_literal(value, f.is_sign_negative()),
+                    suffix.map_or("double", |s| s.c_type()).to_string(),
+                    HashMap::new(),
+                ));
+                Ok("".to_string())
+            }
+            Expression::Int(i, suffix) => {
+                let kind = suffix.map_or("int", |s| s.c_type());
+
+                // only some C types have a literal suffix, the rest are cast
+                let c_suffix = match suffix {
+                    Some(NumSuffix::I64) => "LL",
+                    Some(NumSuffix::U32) => "U",
+                    Some(NumSuffix::U64) => "ULL",
+                    _ => "",
+                };
+                let mut value = CExpr::atom(format!("{}{}", i.unsigned_abs(), c_suffix));
+                value = negate_literal(value, *i < 0);
+
+                if matches!(
+                    suffix,
+                    Some(NumSuffix::I8 | NumSuffix::I16 | NumSuffix::U8 | NumSuffix::U16)
+                ) {
+                    value = CExpr::cast(kind, value);
+                }
 
-                format!("return {};", result)
+                ctx.stack.borrow_mut().push(StackEntry::Value(
+                    value,
+                    kind.to_string(),
+                    HashMap::new(),
+                ));
+                Ok("".to_string())
+            }
+            Expression::Param(p) => body_source(&p.kind, ctx),
+            Expression::Return => {
+                let result = return_value(ctx, "ret")?;
+
+                let mut ret = format!("return {};", result);
+                ret += &ctx.ind();
+
+                Ok(ret)
             }
             Expression::If(expr) => {
-                let StackEntry::Value(cond, _) = ctx.stack.borrow_mut().pop().unwrap() else {
-                    todo!()
+                let (cond, _, _) = ctx.pop_value("if")?;
+                let entry_stack = ctx.stack.borrow().clone();
+
+                let then_branch = branch_source(&expr.body, &entry_stack, ctx)?;
+                let else_branch = match &expr.else_body {
+                    Some(body) => branch_source(body, &entry_stack, ctx)?,
+                    None => Branch {
+                        code: "".to_string(),
+                        stack: entry_stack.clone(),
+                        diverges: false,
+                    },
                 };
-                let mut result = format!("if ({}) {{", cond);
-                *ctx.indent.borrow_mut() += 1;
-                result += &ctx.ind();
 
-                for b in &expr.body {
-                    result += &b.source(ctx);
+                let mut result = "".to_string();
+                let mut then_code = then_branch.code;
+                let mut else_code = else_branch.code;
+
+                let (merged, temps) = match (then_branch.diverges, else_branch.diverges) {
+                    (true, _) => hoist_branch(else_branch.stack, &entry_stack, false, ctx),
+                    (false, true) => hoist_branch(then_branch.stack, &entry_stack, true, ctx),
+                    (false, false) => merge_branches(then_branch.stack, else_branch.stack, ctx)?,
+                };
+
+                for t in &temps {
+                    result += &format!("{} {};", t.kind, t.name);
+                    result += &ctx.ind();
                 }
 
+                // the assignments belong to the `if`, not the last word of
+                // each arm
+                *ctx.indent.borrow_mut() += 1;
+                for (code, assigns) in [
+                    (&mut then_code, temps.iter().any(|t| t.then_value.is_some())),
+                    (&mut else_code, temps.iter().any(|t| t.else_value.is_some())),
+                ] {
+                    if assigns {
+                        *code += &line_directive(&ctx.span);
+                        *code += &ctx.ind();
+                    }
+                }
+                for t in &temps {
+                    if let Some(value) = &t.then_value {
+                        then_code += &format!("{} = {};", t.name, value);
+                        then_code += &ctx.ind();
+                    }
+                    if let Some(value) = &t.else_value {
+                        else_code += &format!("{} = {};", t.name, value);
+                        else_code += &ctx.ind();
+                    }
+                }
                 *ctx.indent.borrow_mut() -= 1;
+
+                *ctx.stack.borrow_mut() = merged;
+
+                result += &format!("if ({}) {{", cond);
+                *ctx.indent.borrow_mut() += 1;
                 result += &ctx.ind();
-                result += &format!("}}");
+                result += then_code.trim_end();
+                *ctx.indent.borrow_mut() -= 1;
                 result += &ctx.ind();
+                result += "}";
 
-                result
+                if !else_code.trim_end().is_empty() {
+                    result += " else {";
+                    *ctx.indent.borrow_mut() += 1;
+                    result += &ctx.ind();
+                    result += else_code.trim_end();
+                    *ctx.indent.borrow_mut() -= 1;
+                    result += &ctx.ind();
+                    result += "}";
+                }
+
+                result += &ctx.ind();
+
+                Ok(result)
             }
-            t => todo!("{:?}", t),
+            Expression::While(expr) => loop_source(Some(&expr.cond), &expr.body, ctx),
+            Expression::Loop(expr) => loop_source(None, &expr.body, ctx),
         }
     }
 }
 
 impl Visitable for Prototype {
-    fn header(&self, ctx: &mut VisitableCtx) -> String {
+    fn header(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
+        builtin::reserved(&self.name, "proc").map_err(|e| ctx.error(e))?;
+
         let mut prefix = "".to_string();
-        let mut result = "".to_string();
+        let mut results = Vec::new();
         if let Some(res) = &self.result {
             for r in res {
-                prefix += &r.source(ctx);
+                ctx.span = r.span.clone();
+                prefix += &r.source(ctx)?;
             }
-            if ctx.stack.borrow_mut().len() != 1 {
-                todo!("{} {:?}", self.name, ctx.stack)
+            ctx.span = self.span.clone();
+
+            let entries: Vec<StackEntry> = ctx.stack.borrow_mut().drain(..).collect();
+            for entry in entries {
+                let StackEntry::Type(t) = entry else {
+                    return Err(ctx.error(CodegenError::ExpectedTypeFoundValue {
+                        word: self.name.clone(),
+                        found: entry.describe(),
+                    }));
+                };
+                results.push(t);
             }
-            for v in ctx.stack.borrow_mut().iter() {
-                let StackEntry::Type(v) = v else { todo!() };
-                result += &v;
+
+            if results.is_empty() {
+                return Err(ctx.error(CodegenError::EmptyResult(self.name.clone())));
             }
 
-            ctx.stack.borrow_mut().clear();
-        } else {
-            result += "void";
+            // `: void` returns nothing, the same as leaving out the `:`
+            if results == ["void"] {
+                results.clear();
+            }
         }
 
-        let ret_kind = result.clone();
+        let ret_kind = match results.len() {
+            0 => "void".to_string(),
+            1 => results[0].clone(),
+            _ => format!("{}{}_result", ctx.inside, c_ident(&self.name)),
+        };
+        let mut result = ret_kind.clone();
 
         result += " ";
         result += &ctx.inside;
-        result += &self.name;
+        result += &c_ident(&self.name);
 
         result += "(";
+        let mut names = Vec::new();
         for r in &self.args {
-            prefix += &r.source(ctx);
+            ctx.span = r.span.clone();
+            let before = ctx.stack.borrow().len();
+            prefix += &r.source(ctx)?;
+            let count = ctx.stack.borrow().len().saturating_sub(before);
+
+            names.resize(ctx.stack.borrow().len(), None);
+            if let Expression::Param(p) = &r.node {
+                builtin::reserved(&p.name, "parameter").map_err(|e| ctx.error(e))?;
+                if count != 1 {
+                    return Err(ctx.error(CodegenError::ParamCount {
+                        name: p.name.clone(),
+                        count,
+                    }));
+                }
+                names[before] = Some(p.name.clone());
+            }
+        }
+        ctx.span = self.span.clone();
+
+        let mut args = Vec::new();
+        for c in ctx.stack.borrow().iter() {
+            let StackEntry::Type(c) = c else {
+                return Err(ctx.error(CodegenError::ExpectedTypeFoundValue {
+                    word: self.name.clone(),
+                    found: c.describe(),
+                }));
+            };
+            args.push(c.clone());
         }
 
         ctx.procs.insert(
             self.name.clone(),
             ProcData {
-                full_name: ctx.inside.clone() + &self.name.clone(),
-                args: ctx.stack.borrow_mut().len() as u8,
-                rets: self.result != None,
+                full_name: ctx.inside.clone() + &c_ident(&self.name),
+                args: args.clone(),
+                names: names.clone(),
+                results,
                 ret_kind,
+                is_extern: false,
             },
         );
 
+        // named parameters are bound by `Proc::source` instead of pushed
         let mut new_stack = Vec::new();
 
         let mut add = false;
-        for (i, c) in ctx.stack.borrow_mut().iter().enumerate() {
-            let StackEntry::Type(c) = c else { todo!() };
+        for (i, (c, name)) in args.iter().zip(&names).enumerate() {
             if add {
                 result += ",";
             }
 
-            let arg = format!("arg_{}", i);
+            let arg = match name {
+                Some(name) => param_ident(name),
+                None => format!("arg_{}", i),
+            };
             add = true;
             result += c;
             result += " ";
             result += &arg;
-            new_stack.push(StackEntry::Value(arg, HashMap::new()));
+            if name.is_none() {
+                new_stack.push(StackEntry::Value(
+                    CExpr::atom(arg),
+                    c.clone(),
+                    HashMap::new(),
+                ));
+            }
         }
 
         *ctx.stack.borrow_mut() = new_stack;
 
         result += ")";
 
-        prefix + &result
+        Ok(prefix + &result)
     }
 }
 
 impl Visitable for Proc {
-    fn source(&self, ctx: &mut VisitableCtx) -> String {
+    fn header(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
+        let mut result = self.def.header(ctx)?;
+
+        let Some(data) = ctx.procs.get_mut(&self.def.name) else {
+            return Ok(result);
+        };
+
+        if self.body == ProcBody::Extern {
+            data.is_extern = true;
+        }
+
+        let data = data.clone();
+        if data.results.len() > 1 {
+            if data.is_extern {
+                return Err(ctx.error(CodegenError::ExternResults {
+                    name: self.def.name.clone(),
+                    count: data.results.len(),
+                }));
+            }
+
+            result = result_struct(&data, ctx) + &result;
+        }
+
+        Ok(result)
+    }
+
+    fn source(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
         match &self.body {
             ProcBody::Impl(body) => {
-                let mut result = self.def.header(ctx);
+                let mut result = line_directive(&self.span);
+                result += &ctx.ind();
+                result += &self.def.header(ctx)?;
                 result += " {";
                 *ctx.indent.borrow_mut() += 1;
 
                 result += &ctx.ind();
 
                 ctx.in_proc = true;
-                for b in body {
-                    result += &b.source(ctx);
+                ctx.push_scope(ScopeKind::Proc);
+
+                ctx.current = ctx.procs.get(&self.def.name).cloned();
+                if let Some(data) = ctx.current.clone() {
+                    ctx.span = self.def.span.clone();
+
+                    for (kind, name) in data.args.iter().zip(&data.names) {
+                        let Some(name) = name else {
+                            continue;
+                        };
+
+                        let arg = CExpr::atom(param_ident(name));
+                        ctx.declare(name, StackEntry::Value(arg, kind.clone(), HashMap::new()));
+                    }
                 }
+
+                result += &body_source(body, ctx)?;
+                ctx.pop_scope();
                 ctx.in_proc = false;
 
-                let mut stk = ctx.stack.borrow_mut();
+                ctx.span = self.span.clone();
 
-                if let Some(StackEntry::Value(ret, _)) = stk.pop() {
-                    result += &format!("return {};", ret);
+                // the checker has made sure only the results are left
+                let results = ctx.current.as_ref().map_or(0, |d| d.results.len());
+                if results > 0 && !diverges(body) {
+                    let ret = return_value(ctx, &self.def.name)?;
 
-                    if stk.len() != 0 {
-                        todo!("{}", result);
-                    }
+                    result += &line_directive(&self.end);
+                    result += &ctx.ind();
+                    result += &format!("return {};", ret);
                 }
+                ctx.current = None;
 
                 *ctx.indent.borrow_mut() -= 1;
 
@@ -493,38 +1544,62 @@ impl Visitable for Proc {
                 result += "}";
                 result += &ctx.ind();
 
-                result
+                Ok(result)
             }
-            _ => "".to_string(),
+            _ => Ok("".to_string()),
             // _ => format!("// extern {}\n", self.def.name),
         }
     }
 }
 
+impl Visitable for Def {
+    fn header(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
+        builtin::reserved(&self.name, "def").map_err(|e| ctx.error(e))?;
+
+        ctx.defs.insert(self.name.clone(), self.clone());
+
+        Ok(doc_comment(&self.doc, ctx))
+    }
+}
+
 impl Visitable for Struct {
-    fn header(&self, ctx: &mut VisitableCtx) -> String {
+    fn header(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
+        builtin::reserved(&self.name, "struct").map_err(|e| ctx.error(e))?;
+
         let mut new_ctx = ctx.clone();
-        new_ctx.inside += &self.name;
+        new_ctx.push_scope(ScopeKind::Struct);
+        new_ctx.inside += &c_ident(&self.name);
         new_ctx.inside += "_";
 
-        if new_ctx.in_struct != None {
-            new_ctx.in_struct = Some(new_ctx.in_struct.clone().unwrap() + &"_" + &self.name);
+        if new_ctx.in_struct.is_some() {
+            new_ctx.in_struct =
+                Some(new_ctx.in_struct.clone().unwrap() + "_" + &c_ident(&self.name));
         } else {
-            new_ctx.in_struct = Some(self.name.clone());
+            new_ctx.in_struct = Some(c_ident(&self.name));
         }
 
         let mut result = "".to_string();
 
+        for d in &self.defs {
+            new_ctx.span = d.span.clone();
+            result += &d.header(&mut new_ctx)?;
+        }
+
         for s in &self.structs {
-            result += &s.header(&mut new_ctx);
+            new_ctx.span = s.span.clone();
+            result += &s.header(&mut new_ctx)?;
         }
 
-        result += &format!("typedef struct {{");
+        result += &doc_comment(&self.doc, ctx);
+        result += &line_directive(&self.span);
+        result += &ctx.ind();
+        result += "typedef struct {";
         *ctx.indent.borrow_mut() += 1;
         result += &ctx.ind();
 
         for s in &self.body {
-            result += &s.header(&mut new_ctx);
+            new_ctx.span = s.span.clone();
+            result += &s.header(&mut new_ctx)?;
         }
 
         result = result.trim_end_matches(' ').to_string();
@@ -535,55 +1610,62 @@ impl Visitable for Struct {
         result += &ctx.ind();
 
         for p in &self.procs {
-            result += &p.def.header(&mut new_ctx);
+            new_ctx.span = p.span.clone();
+            result += &doc_comment(&p.doc, ctx);
+            result += &p.header(&mut new_ctx)?;
             result += ";";
             result += &ctx.ind();
             new_ctx.stack.borrow_mut().clear();
         }
         result += &ctx.ind();
 
-        ctx.vars.insert(
-            self.name.clone(),
-            StackEntry::Struct(new_ctx.clone().in_struct.unwrap(), new_ctx.clone()),
+        ctx.declare(
+            &self.name,
+            StackEntry::Struct(
+                new_ctx.clone().in_struct.unwrap(),
+                Box::new(new_ctx.clone()),
+            ),
         );
 
-        result
+        Ok(result)
     }
 
-    fn source(&self, ctx: &mut VisitableCtx) -> String {
-        let Some(StackEntry::Struct(_, ref mut new_ctx)) = ctx.vars.get_mut(&self.name) else {
-            todo!();
+    fn source(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
+        ctx.span = self.span.clone();
+
+        let Some(StackEntry::Struct(_, ref mut new_ctx)) = ctx.lookup_mut(&self.name) else {
+            return Err(ctx.error(CodegenError::UnknownIdentifier(self.name.clone())));
         };
 
         let mut result = "".to_string();
 
         for p in &self.procs {
-            result += &p.source(new_ctx);
+            new_ctx.span = p.span.clone();
+            result += &p.source(new_ctx)?;
             result += &new_ctx.ind();
         }
 
-        println!("{:?}", new_ctx);
-
-        result
+        Ok(result)
     }
 }
 
 impl Visitable for Include {
-    fn header(&self, ctx: &mut VisitableCtx) -> String {
+    fn header(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
         if self.is_c {
-            return if self.file.starts_with("<") {
+            return Ok(if self.file.starts_with("<") {
                 format!("#include {}", self.file)
             } else {
                 format!("#include \"{}\"", self.file)
-            };
+            });
         }
 
         let file = PathBuf::from(self.file.clone());
 
         let old = ctx.h_file.clone();
 
-        let lex = lexer::Lexer::new(file.clone()).unwrap();
-        let tmp = CarpnFile::parse(&mut lex.peekable()).unwrap();
+        let lex = lexer::Lexer::new(file.clone())
+            .map_err(|e| CarpnError::Io(format!("{}: {}", self.span, e)))?;
+        let tmp = CarpnFile::parse(&mut Tokens::new(lex)?)?;
         let mut h_file = ctx.cache.clone();
         let mut c_file = ctx.cache.clone();
 
@@ -595,24 +1677,22 @@ impl Visitable for Include {
 
         ctx.h_file = format!("{}", h_file.display());
 
-        let h_conts = tmp.header(ctx);
-        let c_conts = tmp.source(ctx);
+        let h_conts = tmp.header(ctx)?;
+        check::check(&tmp, ctx)?;
+        let c_conts = tmp.source(ctx)?;
 
-        let mut file = File::create(c_file.clone()).unwrap();
-        file.write_all(c_conts.as_str().as_bytes()).unwrap();
-
-        let mut file = File::create(h_file.clone()).unwrap();
-        file.write_all(h_conts.as_str().as_bytes()).unwrap();
+        write_file(&c_file, &c_conts)?;
+        write_file(&h_file, &h_conts)?;
 
         ctx.h_file = old;
         ctx.c_files.push(format!("{}", c_file.display()));
 
-        format!("#include \"{}\"", h_file.display())
+        Ok(format!("#include \"{}\"", h_file.display()))
     }
 }
 
 impl Visitable for CarpnFile {
-    fn header(&self, ctx: &mut VisitableCtx) -> String {
+    fn header(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
         let mut result = "".to_string();
 
         let header_name = format!("_{}", ctx.h_file.to_uppercase().replace(".", "_"));
@@ -621,42 +1701,137 @@ impl Visitable for CarpnFile {
         result += &format!("#define {}\n", header_name);
 
         for i in &self.includes {
-            result += &i.header(ctx);
+            ctx.span = i.span.clone();
+            result += &i.header(ctx)?;
             result += &ctx.ind();
         }
 
+        for d in &self.defs {
+            ctx.span = d.span.clone();
+            result += &d.header(ctx)?;
+        }
+
         for s in &self.structs {
-            result += &s.header(ctx);
+            ctx.span = s.span.clone();
+            result += &s.header(ctx)?;
         }
 
         for p in &self.procs {
+            result += &doc_comment(&p.doc, ctx);
             if p.body == ProcBody::Extern {
                 result += "//";
             }
-            result += &p.def.header(ctx);
+            ctx.span = p.span.clone();
+            result += &p.header(ctx)?;
             result += ";";
             result += &ctx.ind();
             ctx.stack.borrow_mut().clear();
         }
 
-        result += &format!("#endif\n");
+        result += "#endif\n";
 
-        result
+        Ok(result)
     }
 
-    fn source(&self, ctx: &mut VisitableCtx) -> String {
+    fn source(&self, ctx: &mut VisitableCtx) -> CodegenResult<String> {
         let mut result = "".to_string();
 
         result += &format!("#include \"{}\"\n", ctx.h_file);
 
         for s in &self.structs {
-            result += &s.source(ctx);
+            result += &s.source(ctx)?;
         }
 
         for p in &self.procs {
-            result += &p.source(ctx);
+            ctx.span = p.span.clone();
+            result += &p.source(ctx)?;
         }
 
-        result
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The C source generated for `src`.
+    fn c(src: &str) -> String {
+        let lex = lexer::Lexer::with_source(PathBuf::from("test.car"), src);
+        let file = CarpnFile::parse(&mut Tokens::new(lex).unwrap()).unwrap();
+
+        let mut ctx = VisitableCtx::for_test();
+        ctx.declare_prelude();
+        file.header(&mut ctx).unwrap();
+        check::check(&file, &ctx).unwrap();
+
+        file.source(&mut ctx).unwrap()
+    }
+
+    #[test]
+    fn values_left_by_the_only_arm_that_falls_through_are_hoisted() {
+        let c = c("proc one : i32 { 1 }
+proc f x:i32 : i32 { x if { one } else { 0 ret } }");
+
+        let declared = c.find("int if_1;").unwrap();
+        assert!(declared < c.find("if (").unwrap());
+        assert!(c.contains("int anon_0 = one();"));
+        assert!(c.contains("if_1 = anon_0;"));
+        assert!(c.trim_end().ends_with("return if_1;\n}"));
+    }
+
+    #[test]
+    fn several_results_are_only_returned_at_the_end_when_it_is_reached() {
+        let returns = |src| c(src).matches("return").count();
+
+        assert_eq!(returns("proc f : i32 i32 { 1 2 ret }"), 1);
+        assert_eq!(
+            returns("proc f x:i32 : i32 i32 { x if { 1 2 ret } 3 4 }"),
+            2
+        );
+    }
+
+    #[test]
+    fn code_after_the_last_word_points_at_its_source() {
+        let c = c("proc one : i32 { 1 }
+proc f x:i32 : i32 {
+    x if {
+        one
+    } else {
+        0 ret
+    }
+}");
+        // the line each statement is blamed on
+        let lines: Vec<(&str, &str)> = c
+            .lines()
+            .zip(c.lines().skip(1))
+            .filter(|(_, code)| !code.trim().starts_with('#'))
+            .map(|(line, code)| (line.trim(), code.trim()))
+            .collect();
+
+        assert!(lines.contains(&("#line 3 \"test.car\"", "if_1 = anon_0;")));
+        assert!(lines.contains(&("#line 8 \"test.car\"", "return if_1;")));
+    }
+
+    #[test]
+    fn literals_and_earlier_values_are_not_hoisted() {
+        let c = c("proc f x:i32 : i32 i32 { x 1 + x if { 5 6 ret } else { 7 } }");
+
+        assert!(!c.contains("if_"));
+        assert!(c.contains("return (f_result){x + 1, 7};"));
+    }
+
+    #[test]
+    fn parameters_keep_their_names_unless_they_collide() {
+        let c = c(
+            "proc f x:i32 int:i32 if_1:i32 y_result:i32 p_z:i32 _Y:i32 : i32 {
+    x int + if_1 + y_result + p_z + _Y +
+}",
+        );
+
+        assert!(c.contains("int f(int x,int p_int,int p_if_1,int p_y_result,int p_p_z,int p__Y)"));
+        assert!(c.contains("return x + p_int + p_if_1 + p_y_result + p_p_z + p__Y;"));
+        assert_eq!(param_ident("if_x"), "if_x");
+        assert_eq!(param_ident("a-b"), "a_2db");
     }
 }
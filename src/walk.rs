@@ -0,0 +1,286 @@
+//! Walkers over the syntax tree, for passes that are not code generation.
+//!
+//! Each `visit_*` method recurses into the node's children by calling the
+//! matching `walk_*` function, so an implementation only overrides the nodes
+//! it cares about and calls `walk_*` itself to keep going below them.
+
+use crate::parser::*;
+use crate::span::Spanned;
+
+pub trait Visit {
+    fn visit_file(&mut self, file: &CarpnFile) {
+        walk_file(self, file);
+    }
+
+    fn visit_include(&mut self, _include: &Include) {}
+
+    fn visit_def(&mut self, def: &Def) {
+        walk_def(self, def);
+    }
+
+    fn visit_struct(&mut self, s: &Struct) {
+        walk_struct(self, s);
+    }
+
+    fn visit_proc(&mut self, p: &Proc) {
+        walk_proc(self, p);
+    }
+
+    fn visit_prototype(&mut self, proto: &Prototype) {
+        walk_prototype(self, proto);
+    }
+
+    /// A list of expressions, such as a proc body or one arm of an `if`.
+    fn visit_body(&mut self, body: &[Spanned<Expression>]) {
+        walk_body(self, body);
+    }
+
+    fn visit_expr(&mut self, expr: &Spanned<Expression>) {
+        walk_expr(self, expr);
+    }
+}
+
+pub fn walk_file<V: Visit + ?Sized>(v: &mut V, file: &CarpnFile) {
+    for i in &file.includes {
+        v.visit_include(i);
+    }
+    for d in &file.defs {
+        v.visit_def(d);
+    }
+    for s in &file.structs {
+        v.visit_struct(s);
+    }
+    for p in &file.procs {
+        v.visit_proc(p);
+    }
+}
+
+pub fn walk_def<V: Visit + ?Sized>(v: &mut V, def: &Def) {
+    v.visit_body(&def.body);
+}
+
+pub fn walk_struct<V: Visit + ?Sized>(v: &mut V, s: &Struct) {
+    v.visit_body(&s.body);
+    for d in &s.defs {
+        v.visit_def(d);
+    }
+    for s in &s.structs {
+        v.visit_struct(s);
+    }
+    for p in &s.procs {
+        v.visit_proc(p);
+    }
+}
+
+pub fn walk_proc<V: Visit + ?Sized>(v: &mut V, p: &Proc) {
+    v.visit_prototype(&p.def);
+    if let ProcBody::Impl(body) = &p.body {
+        v.visit_body(body);
+    }
+}
+
+pub fn walk_prototype<V: Visit + ?Sized>(v: &mut V, proto: &Prototype) {
+    v.visit_body(&proto.args);
+    if let Some(result) = &proto.result {
+        v.visit_body(result);
+    }
+}
+
+pub fn walk_body<V: Visit + ?Sized>(v: &mut V, body: &[Spanned<Expression>]) {
+    for e in body {
+        v.visit_expr(e);
+    }
+}
+
+pub fn walk_expr<V: Visit + ?Sized>(v: &mut V, expr: &Spanned<Expression>) {
+    match &expr.node {
+        Expression::If(i) => {
+            v.visit_body(&i.body);
+            if let Some(else_body) = &i.else_body {
+                v.visit_body(else_body);
+            }
+        }
+        Expression::While(w) => {
+            v.visit_body(&w.cond);
+            v.visit_body(&w.body);
+        }
+        Expression::Loop(l) => v.visit_body(&l.body),
+        Expression::Param(p) => v.visit_body(&p.kind),
+        _ => {}
+    }
+}
+
+/// Like [`Visit`], but able to change the tree as it goes. Bodies are given
+/// as `Vec`s so that a pass can add and remove expressions.
+pub trait VisitMut {
+    fn visit_file_mut(&mut self, file: &mut CarpnFile) {
+        walk_file_mut(self, file);
+    }
+
+    fn visit_include_mut(&mut self, _include: &mut Include) {}
+
+    fn visit_def_mut(&mut self, def: &mut Def) {
+        walk_def_mut(self, def);
+    }
+
+    fn visit_struct_mut(&mut self, s: &mut Struct) {
+        walk_struct_mut(self, s);
+    }
+
+    fn visit_proc_mut(&mut self, p: &mut Proc) {
+        walk_proc_mut(self, p);
+    }
+
+    fn visit_prototype_mut(&mut self, proto: &mut Prototype) {
+        walk_prototype_mut(self, proto);
+    }
+
+    fn visit_body_mut(&mut self, body: &mut Vec<Spanned<Expression>>) {
+        walk_body_mut(self, body);
+    }
+
+    fn visit_expr_mut(&mut self, expr: &mut Spanned<Expression>) {
+        walk_expr_mut(self, expr);
+    }
+}
+
+pub fn walk_file_mut<V: VisitMut + ?Sized>(v: &mut V, file: &mut CarpnFile) {
+    for i in &mut file.includes {
+        v.visit_include_mut(i);
+    }
+    for d in &mut file.defs {
+        v.visit_def_mut(d);
+    }
+    for s in &mut file.structs {
+        v.visit_struct_mut(s);
+    }
+    for p in &mut file.procs {
+        v.visit_proc_mut(p);
+    }
+}
+
+pub fn walk_def_mut<V: VisitMut + ?Sized>(v: &mut V, def: &mut Def) {
+    v.visit_body_mut(&mut def.body);
+}
+
+pub fn walk_struct_mut<V: VisitMut + ?Sized>(v: &mut V, s: &mut Struct) {
+    v.visit_body_mut(&mut s.body);
+    for d in &mut s.defs {
+        v.visit_def_mut(d);
+    }
+    for s in &mut s.structs {
+        v.visit_struct_mut(s);
+    }
+    for p in &mut s.procs {
+        v.visit_proc_mut(p);
+    }
+}
+
+pub fn walk_proc_mut<V: VisitMut + ?Sized>(v: &mut V, p: &mut Proc) {
+    v.visit_prototype_mut(&mut p.def);
+    if let ProcBody::Impl(body) = &mut p.body {
+        v.visit_body_mut(body);
+    }
+}
+
+pub fn walk_prototype_mut<V: VisitMut + ?Sized>(v: &mut V, proto: &mut Prototype) {
+    v.visit_body_mut(&mut proto.args);
+    if let Some(result) = &mut proto.result {
+        v.visit_body_mut(result);
+    }
+}
+
+pub fn walk_body_mut<V: VisitMut + ?Sized>(v: &mut V, body: &mut Vec<Spanned<Expression>>) {
+    for e in body {
+        v.visit_expr_mut(e);
+    }
+}
+
+pub fn walk_expr_mut<V: VisitMut + ?Sized>(v: &mut V, expr: &mut Spanned<Expression>) {
+    match &mut expr.node {
+        Expression::If(i) => {
+            v.visit_body_mut(&mut i.body);
+            if let Some(else_body) = &mut i.else_body {
+                v.visit_body_mut(else_body);
+            }
+        }
+        Expression::While(w) => {
+            v.visit_body_mut(&mut w.cond);
+            v.visit_body_mut(&mut w.body);
+        }
+        Expression::Loop(l) => v.visit_body_mut(&mut l.body),
+        Expression::Param(p) => v.visit_body_mut(&mut p.kind),
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::Lexer;
+    use std::path::PathBuf;
+
+    fn parse(src: &str) -> CarpnFile {
+        let lex = Lexer::with_source(PathBuf::from("test.car"), src);
+        CarpnFile::parse(&mut Tokens::new(lex).unwrap()).unwrap()
+    }
+
+    /// Every identifier in the order the walk reaches it.
+    #[derive(Default)]
+    struct Idents(Vec<String>);
+
+    impl Visit for Idents {
+        fn visit_expr(&mut self, expr: &Spanned<Expression>) {
+            if let Expression::Ident(i) = &expr.node {
+                self.0.push(i.clone());
+            }
+            walk_expr(self, expr);
+        }
+    }
+
+    fn idents(file: &CarpnFile) -> Vec<String> {
+        let mut idents = Idents::default();
+        idents.visit_file(file);
+        idents.0
+    }
+
+    /// Renames every identifier `from` to `to`.
+    struct Rename {
+        from: &'static str,
+        to: &'static str,
+    }
+
+    impl VisitMut for Rename {
+        fn visit_expr_mut(&mut self, expr: &mut Spanned<Expression>) {
+            match &mut expr.node {
+                Expression::Ident(i) if i == self.from => *i = self.to.to_string(),
+                _ => walk_expr_mut(self, expr),
+            }
+        }
+    }
+
+    const NESTED: &str = "def d { a }
+struct S { proc p a : a { a } }
+proc f { b if { c while { d } { e } } else { loop { g if { h } } } }";
+
+    #[test]
+    fn visit_reaches_nested_blocks() {
+        assert_eq!(
+            idents(&parse(NESTED)),
+            ["a", "a", "a", "a", "b", "c", "d", "e", "g", "h"]
+        );
+    }
+
+    #[test]
+    fn visit_mut_rewrites_nested_blocks() {
+        let mut file = parse(NESTED);
+
+        Rename { from: "a", to: "z" }.visit_file_mut(&mut file);
+        Rename { from: "h", to: "y" }.visit_file_mut(&mut file);
+
+        assert_eq!(
+            idents(&file),
+            ["z", "z", "z", "z", "b", "c", "d", "e", "g", "y"]
+        );
+    }
+}